@@ -1,5 +1,3 @@
-#![feature(iter_repeat_n)]
-
 use std::iter::repeat_n;
 
 use calculation_engine::*;
@@ -7,18 +5,24 @@ use criterion::{criterion_group, criterion_main, Criterion};
 
 fn get_simple_expression_nodes() -> Expression {
     let expression = vec![
-        Node::Operand,
+        Node::Variable("a".into()),
         Node::Operator(Operator::Add),
         Node::LeftParens,
-        Node::Operand,
+        Node::Variable("b".into()),
         Node::Operator(Operator::Sub),
-        Node::Operand,
+        Node::Variable("c".into()),
         Node::RightParens,
     ];
     Expression::from_infix(&expression).unwrap()
 }
 
+/// Enough operations and intermediates that streaming every column once per operation is memory bound
+fn get_large_expression() -> Expression {
+    Expression::parse("(a - b) * (a + b) / (c * c + 1) + sqrt(abs(a * c - b)) - max(a, c) * 0.5").unwrap()
+}
+
 const SIZE: usize = 1024 * 8;
+const LARGE_SIZE: usize = 1024 * 1024 * 4;
 
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function(&format!("scalar {}", SIZE), |b| {
@@ -33,6 +37,42 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    c.bench_function(&format!("compiled {}", SIZE), |b| {
+        let expression = get_simple_expression_nodes();
+        let engine = expression.to_compiled_engine();
+        let mut results = vec![0f64; SIZE];
+        let input = vec![1.0, 2.0, 1.0];
+        b.iter(|| {
+            for el in &mut results {
+                *el = engine.evaluate(&input).unwrap();
+            }
+        })
+    });
+
+    // Row at a time engines on an expression where dispatching on every node adds up
+    let mut group = c.benchmark_group(format!("rows {}", SIZE));
+    let rows = (0..SIZE)
+        .map(|i| [i as f64, (i % 7) as f64, 1.0 / (i + 1) as f64])
+        .collect::<Vec<_>>();
+    let mut results = vec![0f64; SIZE];
+    let scalar = get_large_expression().to_scalar_engine();
+    group.bench_function("scalar", |b| {
+        b.iter(|| {
+            for (el, row) in results.iter_mut().zip(&rows) {
+                *el = scalar.evaluate(row).unwrap();
+            }
+        })
+    });
+    let compiled = get_large_expression().to_compiled_engine();
+    group.bench_function("compiled", |b| {
+        b.iter(|| {
+            for (el, row) in results.iter_mut().zip(&rows) {
+                *el = compiled.evaluate(row).unwrap();
+            }
+        })
+    });
+    group.finish();
+
     c.bench_function(&format!("vectorized {}", SIZE), |b| {
         let expression = get_simple_expression_nodes();
         let engine = expression.to_vectorized_engine();
@@ -47,6 +87,38 @@ fn criterion_benchmark(c: &mut Criterion) {
             engine.evaluate(&input, &mut results).unwrap();
         })
     });
+
+    let mut group = c.benchmark_group(format!("vectorized {}", LARGE_SIZE));
+    group.sample_size(20);
+    let engine = get_large_expression().to_vectorized_engine();
+    let input = [
+        (0..LARGE_SIZE).map(|i| i as f64).collect::<Vec<_>>(),
+        (0..LARGE_SIZE).map(|i| (i % 7) as f64).collect::<Vec<_>>(),
+        (0..LARGE_SIZE).map(|i| 1.0 / (i + 1) as f64).collect::<Vec<_>>(),
+    ];
+    let input = input.iter().map(|v| &v[..]).collect::<Vec<_>>();
+    let mut results = vec![0f64; LARGE_SIZE];
+
+    group.bench_function("columns", |b| {
+        b.iter(|| {
+            engine.evaluate(&input, &mut results).unwrap();
+        })
+    });
+    group.bench_function("fused", |b| {
+        b.iter(|| {
+            engine.evaluate_fused(&input, &mut results).unwrap();
+        })
+    });
+    group.bench_function("fused then sum", |b| {
+        b.iter(|| {
+            engine.evaluate_fused(&input, &mut results).unwrap();
+            results.iter().sum::<f64>()
+        })
+    });
+    group.bench_function("reduce sum", |b| {
+        b.iter(|| engine.reduce(&input, engine::reduction::Reduction::Sum).unwrap())
+    });
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
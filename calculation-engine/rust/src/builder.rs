@@ -0,0 +1,226 @@
+use std::ops::{Add, BitAnd, BitOr, Div, Mul, Neg, Not, Sub};
+
+use crate::{Expression, ExpressionConstructionError, Function, Node, Operator, UnaryOperator};
+
+/// A formula built from typed combinators, e.g. `col("a") + col("b") * lit(2.0)`, kept as postfix nodes.
+/// Not `PartialEq`, since `eq` and the other comparisons build formulas.
+#[derive(Clone, Debug)]
+pub struct Formula {
+    pub(crate) nodes: Vec<Node>,
+}
+
+/// The input column of a variable
+pub fn col(name: impl Into<String>) -> Formula {
+    Formula {
+        nodes: vec![Node::Variable(name.into())],
+    }
+}
+
+pub fn lit(value: f64) -> Formula {
+    Formula {
+        nodes: vec![Node::Literal(value)],
+    }
+}
+
+/// Starts `when(condition).then(a).otherwise(b)`, see `Function::If`
+pub fn when(condition: impl Into<Formula>) -> When {
+    When {
+        condition: condition.into(),
+    }
+}
+
+pub struct When {
+    condition: Formula,
+}
+
+impl When {
+    pub fn then(self, then: impl Into<Formula>) -> Then {
+        Then {
+            condition: self.condition,
+            then: then.into(),
+        }
+    }
+}
+
+pub struct Then {
+    condition: Formula,
+    then: Formula,
+}
+
+impl Then {
+    pub fn otherwise(self, otherwise: impl Into<Formula>) -> Formula {
+        Formula::call(Function::If, [self.condition, self.then, otherwise.into()])
+    }
+}
+
+impl Formula {
+    /// Only fails for windows that are too small, see `Function::is_window`
+    pub fn build(self) -> Result<Expression, ExpressionConstructionError> {
+        Expression::from_postfix(self.nodes)
+    }
+
+    pub fn pow(self, exponent: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Pow, exponent.into())
+    }
+
+    pub fn eq(self, other: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Eq, other.into())
+    }
+
+    pub fn ne(self, other: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Ne, other.into())
+    }
+
+    pub fn lt(self, other: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Lt, other.into())
+    }
+
+    pub fn le(self, other: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Le, other.into())
+    }
+
+    pub fn gt(self, other: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Gt, other.into())
+    }
+
+    pub fn ge(self, other: impl Into<Formula>) -> Formula {
+        self.binary(Operator::Ge, other.into())
+    }
+
+    pub fn min(self, other: impl Into<Formula>) -> Formula {
+        Formula::call(Function::Min, [self, other.into()])
+    }
+
+    pub fn max(self, other: impl Into<Formula>) -> Formula {
+        Formula::call(Function::Max, [self, other.into()])
+    }
+
+    pub fn abs(self) -> Formula {
+        Formula::call(Function::Abs, [self])
+    }
+
+    pub fn sqrt(self) -> Formula {
+        Formula::call(Function::Sqrt, [self])
+    }
+
+    pub fn exp(self) -> Formula {
+        Formula::call(Function::Exp, [self])
+    }
+
+    pub fn ln(self) -> Formula {
+        Formula::call(Function::Ln, [self])
+    }
+
+    pub fn clamp(self, lo: impl Into<Formula>, hi: impl Into<Formula>) -> Formula {
+        Formula::call(Function::Clamp, [self, lo.into(), hi.into()])
+    }
+
+    pub fn coalesce(self, fallback: impl Into<Formula>) -> Formula {
+        Formula::call(Function::Coalesce, [self, fallback.into()])
+    }
+
+    pub fn is_null(self) -> Formula {
+        Formula::call(Function::IsNull, [self])
+    }
+
+    pub fn lag(self, rows: u32) -> Formula {
+        Formula::call(Function::Lag, [self, lit(rows.into())])
+    }
+
+    pub fn lead(self, rows: u32) -> Formula {
+        Formula::call(Function::Lead, [self, lit(rows.into())])
+    }
+
+    pub fn rolling_sum(self, rows: u32) -> Formula {
+        Formula::call(Function::RollingSum, [self, lit(rows.into())])
+    }
+
+    pub fn rolling_mean(self, rows: u32) -> Formula {
+        Formula::call(Function::RollingMean, [self, lit(rows.into())])
+    }
+
+    fn binary(mut self, operator: Operator, mut right: Formula) -> Formula {
+        self.nodes.append(&mut right.nodes);
+        self.nodes.push(Node::Operator(operator));
+        self
+    }
+
+    fn unary(mut self, operator: UnaryOperator) -> Formula {
+        self.nodes.push(Node::Unary(operator));
+        self
+    }
+
+    fn call<const A: usize>(function: Function, args: [Formula; A]) -> Formula {
+        Formula::apply(Node::Function(function), args.into())
+    }
+
+    /// Any node applied to formulas of its operands
+    pub(crate) fn apply(node: Node, operands: Vec<Formula>) -> Formula {
+        debug_assert!(node.arity() == operands.len());
+        let mut nodes = operands
+            .into_iter()
+            .flat_map(|operand| operand.nodes)
+            .collect::<Vec<_>>();
+        nodes.push(node);
+        Formula { nodes }
+    }
+}
+
+impl From<f64> for Formula {
+    fn from(value: f64) -> Self {
+        lit(value)
+    }
+}
+
+impl TryFrom<Formula> for Expression {
+    type Error = ExpressionConstructionError;
+
+    fn try_from(formula: Formula) -> Result<Self, Self::Error> {
+        formula.build()
+    }
+}
+
+/// `Formula op Formula`, `Formula op f64` and `f64 op Formula`
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $operator:expr) => {
+        impl<R: Into<Formula>> $trait<R> for Formula {
+            type Output = Formula;
+
+            fn $method(self, right: R) -> Formula {
+                self.binary($operator, right.into())
+            }
+        }
+
+        impl $trait<Formula> for f64 {
+            type Output = Formula;
+
+            fn $method(self, right: Formula) -> Formula {
+                lit(self).binary($operator, right)
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, Operator::Add);
+binary_operator!(Sub, sub, Operator::Sub);
+binary_operator!(Mul, mul, Operator::Mul);
+binary_operator!(Div, div, Operator::Div);
+// Logical, like `&` and `|` on `bool`
+binary_operator!(BitAnd, bitand, Operator::And);
+binary_operator!(BitOr, bitor, Operator::Or);
+
+impl Neg for Formula {
+    type Output = Formula;
+
+    fn neg(self) -> Formula {
+        self.unary(UnaryOperator::Neg)
+    }
+}
+
+impl Not for Formula {
+    type Output = Formula;
+
+    fn not(self) -> Formula {
+        self.unary(UnaryOperator::Not)
+    }
+}
@@ -0,0 +1,222 @@
+use crate::builder::{lit, when, Formula};
+use crate::{Expression, Function, Node, Operator, UnaryOperator};
+
+/// Derivative of an operand, `None` where it is 0 everywhere, so that `x * 0` is never built.
+type Derivative = Option<Formula>;
+
+/// Differentiates the postfix nodes one at a time with the chain rule, taking one side at kinks such as `abs` at 0.
+pub(crate) fn derivative(expression: &Expression, variable: &str) -> Expression {
+    // Formula and derivative of every operand on the stack
+    let mut stack: Vec<(Formula, Derivative)> = Vec::with_capacity(expression.max_stack_depth);
+
+    for node in &expression.nodes {
+        let (values, derivatives): (Vec<_>, Vec<_>) = stack.split_off(stack.len() - node.arity()).into_iter().unzip();
+        let derivative = differentiate(node, &values, derivatives, variable);
+        stack.push((Formula::apply(node.clone(), values), derivative));
+    }
+
+    let (_, derivative) = stack.pop().unwrap();
+    let nodes = derivative.unwrap_or_else(|| lit(0.0)).nodes;
+    let max_stack_depth = Expression::max_stack_depth(&nodes).expect("derivatives are built from whole operands");
+    // Same inputs as the expression, even for variables that the derivative doesn't depend on
+    let mut variables = expression.variables.clone();
+    let operands = Expression::operands(&nodes, &mut variables);
+
+    Expression {
+        nodes,
+        variables,
+        operands,
+        max_stack_depth,
+    }
+    .optimize()
+}
+
+fn differentiate(node: &Node, values: &[Formula], derivatives: Vec<Derivative>, variable: &str) -> Derivative {
+    let value = |k: usize| values[k].clone();
+    let mut derivatives = derivatives.into_iter();
+    let mut derivative = || derivatives.next().unwrap();
+
+    match node {
+        Node::Variable(name) => (name == variable).then(|| lit(1.0)),
+        Node::Literal(_) => None,
+        Node::Unary(UnaryOperator::Plus) => derivative(),
+        Node::Unary(UnaryOperator::Neg) => derivative().map(|d| -d),
+        Node::Operator(Operator::Add) => sum(derivative(), derivative()),
+        Node::Operator(Operator::Sub) => difference(derivative(), derivative()),
+        Node::Operator(Operator::Mul) => sum(derivative().map(|d| d * value(1)), derivative().map(|d| value(0) * d)),
+        Node::Operator(Operator::Div) => sum(
+            derivative().map(|d| d / value(1)),
+            derivative().map(|d| -(value(0) * d) / value(1).pow(2.0)),
+        ),
+        // `x ^ 0` is 1 even where `x ^ -1` is infinite
+        Node::Operator(Operator::Pow) if values[1].nodes == [Node::Literal(0.0)] => None,
+        Node::Operator(Operator::Pow) => sum(
+            derivative().map(|d| value(1) * value(0).pow(value(1) - 1.0) * d),
+            derivative().map(|d| value(0).pow(value(1)) * value(0).ln() * d),
+        ),
+        Node::Unary(UnaryOperator::Not)
+        | Node::Operator(
+            Operator::Eq
+            | Operator::Ne
+            | Operator::Lt
+            | Operator::Le
+            | Operator::Gt
+            | Operator::Ge
+            | Operator::And
+            | Operator::Or,
+        )
+        | Node::Function(Function::IsNull) => None,
+        Node::Function(Function::Min) => choose(value(0).le(value(1)), derivative(), derivative()),
+        Node::Function(Function::Max) => choose(value(0).ge(value(1)), derivative(), derivative()),
+        Node::Function(Function::Abs) => derivative().map(|d| when(value(0).lt(0.0)).then(-d.clone()).otherwise(d)),
+        Node::Function(Function::Sqrt) => derivative().map(|d| d / (2.0 * value(0).sqrt())),
+        Node::Function(Function::Exp) => derivative().map(|d| value(0).exp() * d),
+        Node::Function(Function::Ln) => derivative().map(|d| d / value(0)),
+        // `max(x, lo)` is `hi` wherever it is larger, otherwise `x` unless it is below `lo`
+        Node::Function(Function::Clamp) => {
+            let (x, lo, hi) = (derivative(), derivative(), derivative());
+            let below = choose(value(0).lt(value(1)), lo, x);
+            choose(value(0).max(value(1)).gt(value(2)), hi, below)
+        }
+        Node::Function(Function::If) => {
+            let _ = derivative();
+            choose(value(0), derivative(), derivative())
+        }
+        Node::Function(Function::Coalesce) => {
+            let x = derivative();
+            choose(value(0).is_null(), derivative(), x)
+        }
+        // Linear in the rows they read, the window size stays the same
+        Node::Function(Function::Lag | Function::Lead | Function::RollingSum | Function::RollingMean) => {
+            derivative().map(|d| Formula::apply(node.clone(), vec![d, value(1)]))
+        }
+        Node::LeftParens | Node::RightParens | Node::Separator => unreachable!("not part of postfix"),
+    }
+}
+
+fn sum(left: Derivative, right: Derivative) -> Derivative {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left + right),
+        (left, right) => left.or(right),
+    }
+}
+
+fn difference(left: Derivative, right: Derivative) -> Derivative {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left - right),
+        (left, right) => left.or(right.map(|right| -right)),
+    }
+}
+
+/// `if(condition, then, otherwise)`, unless both are 0
+fn choose(condition: Formula, then: Derivative, otherwise: Derivative) -> Derivative {
+    if then.is_none() && otherwise.is_none() {
+        return None;
+    }
+    let zero = || lit(0.0);
+    Some(
+        when(condition)
+            .then(then.unwrap_or_else(zero))
+            .otherwise(otherwise.unwrap_or_else(zero)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derivative(source: &str, variable: &str) -> String {
+        Expression::parse(source).unwrap().derivative(variable).to_string()
+    }
+
+    #[test]
+    fn simplification() {
+        assert_eq!(derivative("a * a + 3 * b", "a"), "a + a");
+        assert_eq!(derivative("a * a + 3 * b", "b"), "3");
+        assert_eq!(derivative("a * a + 3 * b", "c"), "0");
+        assert_eq!(derivative("x ^ 3 - x / 4", "x"), "3 * x ^ 2 - 0.25");
+        assert_eq!(derivative("exp(2 * x)", "x"), "exp(2 * x) * 2");
+        assert_eq!(derivative("x ^ 0 + (x > 1)", "x"), "0");
+        assert_eq!(derivative("if(a > b, a, b)", "a"), "if(a > b, 1, 0)");
+    }
+
+    #[test]
+    fn inputs_are_kept() {
+        let expression = Expression::parse("a + b * c").unwrap().derivative("b");
+        assert_eq!(expression.variables(), ["a", "b", "c"]);
+        assert_eq!(expression.to_scalar_engine().evaluate(&[1.0, 2.0, 3.0]).unwrap(), 3.0);
+    }
+
+    /// Compares every partial derivative to a central difference, with both engines
+    #[test]
+    fn finite_differences() {
+        let sources = [
+            "a * b + c",
+            "a / b - b / (c + a)",
+            "(a + 1) ^ 3 * b ^ 2",
+            "a ^ b + c ^ a",
+            "sqrt(a * b) + exp(-c) * ln(a + c)",
+            "-abs(a - 2 * b) + +c",
+            "min(a, b) * max(b, c)",
+            "clamp(a * c, b, 4) + clamp(a, 0, 0.5)",
+            "if(a > b, a * c, b / c) + (a < c) * a",
+            "coalesce(a * b, c)",
+            "not a + (a and b) * c",
+        ];
+        let rows = [[0.7, 1.3, 2.1], [2.5, 0.4, 0.9], [1.1, 3.2, 0.3], [4.0, 1.5, 2.2]];
+        let h = 1e-6;
+
+        for source in sources {
+            let expression = Expression::parse(source).unwrap();
+            let inputs = expression.required_input_length();
+            let f = expression.clone().to_scalar_engine();
+
+            for (k, variable) in expression.variables().iter().enumerate() {
+                let derivative = expression.derivative(variable);
+                let scalar = derivative.clone().to_scalar_engine();
+                let vectorized = derivative.clone().to_vectorized_engine();
+
+                let columns = (0..inputs)
+                    .map(|i| rows.iter().map(|row| row[i]).collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+                let columns = columns.iter().map(|column| &column[..]).collect::<Vec<_>>();
+                let mut output = vec![0.0; rows.len()];
+                vectorized.evaluate(&columns, &mut output).unwrap();
+
+                for (row, &vectorized) in rows.iter().zip(&output) {
+                    let row = &row[..inputs];
+                    let shifted = |offset: f64| {
+                        let mut row = row.to_vec();
+                        row[k] += offset;
+                        f.evaluate(&row).unwrap()
+                    };
+                    let expected = (shifted(h) - shifted(-h)) / (2.0 * h);
+                    let result = scalar.evaluate(row).unwrap();
+
+                    let tolerance = 1e-5 * expected.abs().max(1.0);
+                    assert!(
+                        (result - expected).abs() < tolerance,
+                        "d/d{variable} {source} at {row:?}: {result} ({derivative}) != {expected}"
+                    );
+                    assert_eq!(
+                        vectorized.to_bits(),
+                        result.to_bits(),
+                        "d/d{variable} {source} at {row:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn windows() {
+        // Every row of the column moves at once
+        let derivative = Expression::parse("a * lag(a, 1) + rolling_sum(a, 2)")
+            .unwrap()
+            .derivative("a");
+        let a = [1.0, 2.0, 3.0, 4.0];
+        let mut output = vec![0.0; a.len()];
+        derivative.to_vectorized_engine().evaluate(&[&a], &mut output).unwrap();
+        assert_eq!(output[1..], [1.0 + 2.0 + 2.0, 2.0 + 3.0 + 2.0, 3.0 + 4.0 + 2.0]);
+    }
+}
@@ -0,0 +1,609 @@
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, OnceLock};
+
+use thiserror::Error;
+
+use crate::engine::element::FloatPolicy;
+use crate::engine::pool::ThreadPool;
+use crate::engine::{vectorized, EvaluationError};
+use crate::{Expression, Function, Node, Operator, UnaryOperator};
+
+/// Compares `scalar::Engine` with the vectorized engines on random expressions and inputs without windows.
+/// The first disagreement is shrunk to a small case that still disagrees, see `Mismatch`.
+#[derive(Clone, Debug)]
+pub struct Checker {
+    seed: u64,
+    cases: usize,
+    max_depth: usize,
+    max_rows: usize,
+    tolerance: f64,
+    /// Builds the engine that is compared with the scalar one, replaced in tests to make them disagree
+    vectorized: fn(Expression) -> vectorized::Engine,
+    /// For `evaluate_parallel`, started by the first comparison and shared by clones
+    pool: Arc<OnceLock<ThreadPool>>,
+}
+
+/// Why `Checker::check` failed
+#[derive(Error, Debug)]
+pub enum CheckError {
+    #[error("no input was provided for variable '{0}'")]
+    MissingInput(String),
+    #[error("column '{0}' has {1} rows instead of {2}")]
+    InvalidColumnLength(String, usize, usize),
+    #[error("the input has no rows")]
+    EmptyInput,
+    #[error("{0}")]
+    Mismatch(Box<Mismatch>),
+}
+
+/// A case where the engines disagree, which can be passed to `Checker::check` to reproduce it
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub expression: Expression,
+    /// Input columns by variable name
+    pub input: Vec<(String, Vec<f64>)>,
+    /// The evaluation method of `vectorized::Engine` that disagreed
+    pub method: &'static str,
+    /// First row where the results differ
+    pub row: usize,
+    pub scalar: Result<f64, String>,
+    pub vectorized: Result<f64, String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "engines disagree on `{}` at row {}", self.expression, self.row)?;
+        for (variable, column) in &self.input {
+            writeln!(f, "  {variable} = {column:?}")?;
+        }
+        writeln!(f, "  scalar: {:?}", self.scalar)?;
+        write!(f, "  vectorized {}: {:?}", self.method, self.vectorized)
+    }
+}
+
+impl Checker {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            cases: 1000,
+            max_depth: 5,
+            max_rows: 37,
+            tolerance: 1e-12,
+            vectorized: Expression::to_vectorized_engine,
+            pool: Arc::default(),
+        }
+    }
+
+    /// Number of random expressions, each over its own random input
+    pub fn with_cases(mut self, cases: usize) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn with_max_rows(mut self, rows: usize) -> Self {
+        self.max_rows = rows.max(1);
+        self
+    }
+
+    /// Relative difference that finite results may have, NaN only agrees with NaN and infinity with itself
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Checks every case, returning the first mismatch after shrinking it
+    pub fn run(&self) -> Result<(), Box<Mismatch>> {
+        let mut random = Random(self.seed);
+        for _ in 0..self.cases {
+            let tree = Tree::generate(&mut random, self.max_depth);
+            let rows = 1 + random.below(self.max_rows as u64) as usize;
+            let input = VARIABLES
+                .iter()
+                .map(|&variable| (variable.to_string(), (0..rows).map(|_| random.value()).collect()))
+                .collect::<Vec<_>>();
+            self.check_tree(tree, input)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `run`, but panics with the shrunk mismatch
+    pub fn assert(&self) {
+        if let Err(mismatch) = self.run() {
+            panic!("{mismatch}");
+        }
+    }
+
+    /// Checks a single case, where `input` has a column for every variable of `expression`, all of the same length.
+    /// A mismatch is shrunk the same way as in `run`.
+    pub fn check(&self, expression: &Expression, input: &[(&str, &[f64])]) -> Result<(), CheckError> {
+        if let Some(variable) = expression
+            .variables()
+            .iter()
+            .find(|&variable| !input.iter().any(|(name, _)| name == variable))
+        {
+            return Err(CheckError::MissingInput(variable.clone()));
+        }
+        let rows = input.first().map_or(0, |(_, column)| column.len());
+        if let Some((variable, column)) = input.iter().find(|(_, column)| column.len() != rows) {
+            return Err(CheckError::InvalidColumnLength(
+                variable.to_string(),
+                column.len(),
+                rows,
+            ));
+        }
+        if rows == 0 {
+            return Err(CheckError::EmptyInput);
+        }
+
+        let input = input
+            .iter()
+            .map(|(variable, column)| (variable.to_string(), column.to_vec()))
+            .collect();
+        self.check_tree(Tree::from_expression(expression), input)
+            .map_err(CheckError::Mismatch)
+    }
+
+    fn check_tree(&self, tree: Tree, input: Input) -> Result<(), Box<Mismatch>> {
+        let Some(mismatch) = self.compare(&tree, &input) else {
+            return Ok(());
+        };
+
+        let (tree, input) = shrink(tree, input, |tree, input| self.compare(tree, input).is_some());
+        Err(Box::new(self.compare(&tree, &input).unwrap_or(mismatch)))
+    }
+
+    /// The first row where the engines disagree, `None` when they agree on all of them or there are no rows
+    fn compare(&self, tree: &Tree, input: &Input) -> Option<Mismatch> {
+        let expression = tree.to_expression();
+        let columns = expression
+            .variables()
+            .iter()
+            .map(|variable| Some(&input.iter().find(|(name, _)| name == variable)?.1[..]))
+            .collect::<Option<Vec<_>>>()?;
+        let rows = input.first().map_or(0, |(_, column)| column.len());
+        if rows == 0 {
+            return None;
+        }
+        let pool = self.pool.get_or_init(|| ThreadPool::new(NonZeroUsize::new(3).unwrap()));
+
+        for float in [FloatPolicy::Propagate, FloatPolicy::Error] {
+            let scalar = expression.clone().to_scalar_engine().with_float_policy(float);
+            let scalar = (0..rows)
+                .map(|j| {
+                    let row = columns.iter().map(|column| column[j]).collect::<Vec<_>>();
+                    scalar.evaluate(&row).map_err(|error| error.to_string())
+                })
+                .collect::<Vec<_>>();
+
+            let engine = (self.vectorized)(expression.clone()).with_float_policy(float);
+            for (method, evaluate) in METHODS {
+                // Rows that aren't written stay NaN, unless the scalar engine computed NaN as well
+                let mut output = vec![f64::NAN; rows];
+                let result = evaluate(&engine, &columns, &mut output, pool);
+                let method = match float {
+                    FloatPolicy::Error => method.1,
+                    _ => method.0,
+                };
+                let mismatch = |row: usize, vectorized: Result<f64, String>| Mismatch {
+                    expression: expression.clone(),
+                    input: input.clone(),
+                    method,
+                    row,
+                    scalar: scalar[row].clone(),
+                    vectorized,
+                };
+
+                match result {
+                    // Columns are evaluated one operation at a time, so the row that is reported
+                    // isn't necessarily the first one where the scalar engine fails, but it must fail there
+                    Err(error) => {
+                        let row = match error {
+                            EvaluationError::Overflow { row, .. } | EvaluationError::NonFinite { row, .. } => row,
+                            _ => 0,
+                        };
+                        if row >= rows || scalar[row].is_ok() {
+                            return Some(mismatch(row.min(rows - 1), Err(error.to_string())));
+                        }
+                    }
+                    Ok(()) => {
+                        for (row, scalar) in scalar.iter().enumerate() {
+                            let agree = match scalar {
+                                Ok(scalar) => self.agree(*scalar, output[row]),
+                                Err(_) => false,
+                            };
+                            if !agree {
+                                return Some(mismatch(row, Ok(output[row])));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn agree(&self, scalar: f64, vectorized: f64) -> bool {
+        (scalar.is_nan() && vectorized.is_nan())
+            || scalar == vectorized
+            || (scalar.is_finite()
+                && vectorized.is_finite()
+                && (scalar - vectorized).abs() <= self.tolerance * scalar.abs().max(vectorized.abs()))
+    }
+}
+
+type Method = fn(&vectorized::Engine, &[&[f64]], &mut [f64], &ThreadPool) -> Result<(), EvaluationError>;
+
+/// Evaluation methods by their name without and with `FloatPolicy::Error`
+const METHODS: [((&str, &str), Method); 3] = [
+    (
+        ("evaluate", "evaluate with FloatPolicy::Error"),
+        |engine, input, output, _| engine.evaluate(input, output),
+    ),
+    (
+        ("evaluate_fused", "evaluate_fused with FloatPolicy::Error"),
+        |engine, input, output, _| engine.evaluate_fused(input, output),
+    ),
+    (
+        ("evaluate_parallel", "evaluate_parallel with FloatPolicy::Error"),
+        |engine, input, output, pool| engine.evaluate_parallel(input, output, pool),
+    ),
+];
+
+/// Input columns by variable name, all of the same length
+type Input = Vec<(String, Vec<f64>)>;
+
+const VARIABLES: [&str; 3] = ["a", "b", "c"];
+
+/// Values that the engines are most likely to treat differently
+const EDGE_VALUES: [f64; 12] = [
+    f64::NAN,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+    0.0,
+    -0.0,
+    // Subnormals
+    f64::MIN_POSITIVE / 4.0,
+    -f64::MIN_POSITIVE / 4.0,
+    f64::MIN_POSITIVE,
+    f64::MAX,
+    1.0,
+    -1.0,
+    0.5,
+];
+
+/// Literal exponents, including the small integers of `plan::integer_exponent`
+const EXPONENTS: [f64; 9] = [0.5, -1.5, f64::NAN, 0.0, 1.0, 2.0, 3.0, -1.0, -2.0];
+
+const UNARY_OPERATORS: [UnaryOperator; 3] = [UnaryOperator::Plus, UnaryOperator::Neg, UnaryOperator::Not];
+
+const OPERATORS: [Operator; 12] = [
+    Operator::Add,
+    Operator::Sub,
+    Operator::Mul,
+    Operator::Div,
+    Operator::Eq,
+    Operator::Ne,
+    Operator::Lt,
+    Operator::Le,
+    Operator::Gt,
+    Operator::Ge,
+    Operator::And,
+    Operator::Or,
+];
+
+const FUNCTIONS: [Function; 10] = [
+    Function::Min,
+    Function::Max,
+    Function::Abs,
+    Function::Sqrt,
+    Function::Exp,
+    Function::Ln,
+    Function::Clamp,
+    Function::If,
+    Function::Coalesce,
+    Function::IsNull,
+];
+
+/// Expression as a tree, which is easier to generate and shrink than postfix
+#[derive(Clone, Debug, PartialEq)]
+enum Tree {
+    Variable(String),
+    Literal(f64),
+    Unary(UnaryOperator, Box<Tree>),
+    Binary(Operator, Box<Tree>, Box<Tree>),
+    Call(Function, Vec<Tree>),
+}
+
+impl Tree {
+    fn generate(random: &mut Random, depth: usize) -> Tree {
+        if depth == 0 || random.below(4) == 0 {
+            return match random.below(3) {
+                0 => Tree::Literal(random.value()),
+                _ => Tree::Variable(random.pick(&VARIABLES).to_string()),
+            };
+        }
+
+        let child = |random: &mut Random| Box::new(Tree::generate(random, depth - 1));
+        match random.below(8) {
+            0 => Tree::Unary(*random.pick(&UNARY_OPERATORS), child(random)),
+            1..=4 => Tree::Binary(*random.pick(&OPERATORS), child(random), child(random)),
+            5 => {
+                let exponent = match random.below(2) {
+                    0 => Tree::Literal(*random.pick(&EXPONENTS)),
+                    _ => Tree::Variable(random.pick(&VARIABLES).to_string()),
+                };
+                Tree::Binary(Operator::Pow, child(random), Box::new(exponent))
+            }
+            _ => {
+                let function = *random.pick(&FUNCTIONS);
+                Tree::Call(
+                    function,
+                    (0..function.arity())
+                        .map(|_| Tree::generate(random, depth - 1))
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn from_expression(expression: &Expression) -> Tree {
+        let mut stack: Vec<Tree> = Vec::with_capacity(expression.max_stack_depth);
+        for node in &expression.nodes {
+            let tree = match node {
+                Node::Variable(name) => Tree::Variable(name.clone()),
+                Node::Literal(value) => Tree::Literal(*value),
+                Node::Unary(operator) => Tree::Unary(*operator, Box::new(stack.pop().unwrap())),
+                Node::Operator(operator) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    Tree::Binary(*operator, Box::new(left), Box::new(right))
+                }
+                Node::Function(function) => Tree::Call(*function, stack.split_off(stack.len() - function.arity())),
+                Node::LeftParens | Node::RightParens | Node::Separator => unreachable!("not part of postfix"),
+            };
+            stack.push(tree);
+        }
+
+        stack.pop().unwrap()
+    }
+
+    fn to_expression(&self) -> Expression {
+        let mut nodes = Vec::new();
+        self.postfix(&mut nodes);
+        Expression::from_postfix(nodes).expect("trees have an operand for every argument")
+    }
+
+    fn postfix(&self, nodes: &mut Vec<Node>) {
+        match self {
+            Tree::Variable(name) => nodes.push(Node::Variable(name.clone())),
+            Tree::Literal(value) => nodes.push(Node::Literal(*value)),
+            Tree::Unary(operator, x) => {
+                x.postfix(nodes);
+                nodes.push(Node::Unary(*operator));
+            }
+            Tree::Binary(operator, left, right) => {
+                left.postfix(nodes);
+                right.postfix(nodes);
+                nodes.push(Node::Operator(*operator));
+            }
+            Tree::Call(function, args) => {
+                args.iter().for_each(|arg| arg.postfix(nodes));
+                nodes.push(Node::Function(*function));
+            }
+        }
+    }
+
+    fn children(&self) -> Vec<&Tree> {
+        match self {
+            Tree::Variable(_) | Tree::Literal(_) => Vec::new(),
+            Tree::Unary(_, x) => vec![x],
+            Tree::Binary(_, left, right) => vec![left, right],
+            Tree::Call(_, args) => args.iter().collect(),
+        }
+    }
+
+    /// The same tree with child `k` replaced
+    fn with_child(&self, k: usize, child: Tree) -> Tree {
+        let mut tree = self.clone();
+        match &mut tree {
+            Tree::Variable(_) | Tree::Literal(_) => unreachable!("leaves have no children"),
+            Tree::Unary(_, x) => **x = child,
+            Tree::Binary(_, left, right) => *[left, right][k].as_mut() = child,
+            Tree::Call(_, args) => args[k] = child,
+        }
+        tree
+    }
+
+    /// Smaller trees, from the smallest: one of the children, a literal, or the same tree with a smaller child
+    fn shrink(&self) -> Vec<Tree> {
+        let mut smaller = self.children().into_iter().cloned().collect::<Vec<_>>();
+        match self {
+            Tree::Literal(value) => smaller.extend(simpler(*value).map(Tree::Literal)),
+            Tree::Variable(_) => {}
+            _ => smaller.extend([Tree::Literal(0.0), Tree::Literal(1.0)]),
+        }
+
+        for (k, child) in self.children().into_iter().enumerate() {
+            smaller.extend(child.shrink().into_iter().map(|child| self.with_child(k, child)));
+        }
+
+        smaller
+    }
+}
+
+/// Greedily replaces the tree and the input with smaller ones for which `fails` still holds, until none does
+fn shrink(mut tree: Tree, mut input: Input, fails: impl Fn(&Tree, &Input) -> bool) -> (Tree, Input) {
+    'shrink: loop {
+        for smaller in tree.shrink() {
+            if fails(&smaller, &input) {
+                tree = smaller;
+                continue 'shrink;
+            }
+        }
+
+        let rows = input.first().map_or(0, |(_, column)| column.len());
+        // Without any one of the rows
+        for j in 0..rows {
+            let smaller = input
+                .iter()
+                .map(|(variable, column)| {
+                    let column = column.iter().enumerate().filter(|&(i, _)| i != j).map(|(_, &v)| v);
+                    (variable.clone(), column.collect())
+                })
+                .collect();
+            if rows > 1 && fails(&tree, &smaller) {
+                input = smaller;
+                continue 'shrink;
+            }
+        }
+        for (k, j) in (0..input.len()).flat_map(|k| (0..rows).map(move |j| (k, j))) {
+            for value in simpler(input[k].1[j]) {
+                let mut smaller = input.clone();
+                smaller[k].1[j] = value;
+                if fails(&tree, &smaller) {
+                    input = smaller;
+                    continue 'shrink;
+                }
+            }
+        }
+
+        break;
+    }
+
+    // Columns of variables that are no longer part of the expression, one is kept for the number of rows
+    let variables = tree.to_expression().variables().to_vec();
+    if input.iter().any(|(variable, _)| variables.contains(variable)) {
+        input.retain(|(variable, _)| variables.contains(variable));
+    } else {
+        input.truncate(1);
+    }
+    (tree, input)
+}
+
+/// Values to try instead of `value` while shrinking, 0 is the simplest and then 1.
+/// Compared bit for bit, since -0 equals 0.
+fn simpler(value: f64) -> impl Iterator<Item = f64> {
+    [0.0, 1.0]
+        .into_iter()
+        .take_while(move |v: &f64| v.to_bits() != value.to_bits())
+}
+
+/// SplitMix64, which is all that is needed to make the cases of a seed reproducible
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn pick<'a, T>(&mut self, values: &'a [T]) -> &'a T {
+        &values[self.below(values.len() as u64) as usize]
+    }
+
+    /// An edge value half of the time, otherwise a small number with a fraction
+    fn value(&mut self) -> f64 {
+        match self.below(2) {
+            0 => *self.pick(&EDGE_VALUES),
+            _ => (self.below(2001) as f64 - 1000.0) / 8.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engines_agree() {
+        for seed in 0..4 {
+            Checker::new(seed).assert();
+        }
+        // Columns that span several fused chunks
+        Checker::new(4).with_cases(20).with_max_rows(2500).assert();
+    }
+
+    #[test]
+    fn mismatches_shrink() {
+        let expression = Expression::parse("(a + b) * if(c > 1, sqrt(a - c), max(b, 2))").unwrap();
+        let tree = Tree::from_expression(&expression);
+        let input = VARIABLES
+            .iter()
+            .map(|&variable| (variable.to_string(), vec![3.0, -2.0, 5.0]))
+            .collect();
+
+        let (tree, input) = shrink(tree, input, |tree, _| {
+            tree.to_expression().nodes.contains(&Node::Function(Function::Sqrt))
+        });
+        assert_eq!(tree.to_expression().to_string(), "sqrt(a)");
+        assert_eq!(input, [("a".to_string(), vec![0.0])]);
+    }
+
+    #[test]
+    fn disagreements_are_found() {
+        let mut checker = Checker::new(0);
+        // A vectorized engine that evaluates `min` as `max`
+        checker.vectorized = |expression| {
+            let nodes = expression.nodes.into_iter().map(|node| match node {
+                Node::Function(Function::Min) => Node::Function(Function::Max),
+                node => node,
+            });
+            Expression::from_postfix(nodes.collect())
+                .unwrap()
+                .to_vectorized_engine()
+        };
+
+        // Shrunk to the smallest expression and input
+        let mismatch = checker.run().unwrap_err();
+        assert_eq!(mismatch.expression.to_string(), "min(0, 1)");
+        assert_eq!(mismatch.input, [("a".to_string(), vec![0.0])]);
+        assert_eq!((mismatch.method, mismatch.row), ("evaluate", 0));
+        assert_eq!((mismatch.scalar, mismatch.vectorized), (Ok(0.0), Ok(1.0)));
+        assert!(checker.check(&mismatch.expression, &[("a", &[0.0])]).is_err());
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let checker = Checker::new(0).with_tolerance(0.0);
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert!(checker
+            .check(&Expression::parse("a * 2 + 1").unwrap(), &[("a", &a)])
+            .is_ok());
+
+        let expression = Expression::parse("a * b").unwrap();
+        let result = checker.check(&expression, &[("a", &a)]);
+        assert!(matches!(result, Err(CheckError::MissingInput(variable)) if variable == "b"));
+        let result = checker.check(&expression, &[("a", &a), ("b", &a[1..])]);
+        assert!(matches!(result, Err(CheckError::InvalidColumnLength(variable, 4, 5)) if variable == "b"));
+        let result = checker.check(&expression, &[("a", &[]), ("b", &[])]);
+        assert!(matches!(result, Err(CheckError::EmptyInput)));
+
+        let mismatch = Mismatch {
+            expression: Expression::parse("a / b").unwrap(),
+            input: vec![("a".into(), vec![1.0]), ("b".into(), vec![-0.0])],
+            method: "evaluate",
+            row: 0,
+            scalar: Ok(f64::NEG_INFINITY),
+            vectorized: Ok(f64::INFINITY),
+        };
+        assert_eq!(
+            mismatch.to_string(),
+            "engines disagree on `a / b` at row 0\n  a = [1.0]\n  b = [-0.0]\n  scalar: Ok(-inf)\n  vectorized evaluate: Ok(inf)"
+        );
+    }
+}
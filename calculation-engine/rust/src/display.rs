@@ -0,0 +1,170 @@
+use std::fmt;
+
+use crate::precedence::{self, SIGN_PRECEDENCE};
+use crate::{Expression, Node, Operator, UnaryOperator};
+
+/// Precedence of anything that never needs parens, such as variables and function calls
+const ATOM_PRECEDENCE: i32 = i32::MAX;
+
+/// Prints infix with only the parens that are needed to parse back into the same nodes, e.g. `a - (b - c) * d`.
+/// Negative, NaN and infinite literals are printed as the operations that compute them.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Infix of every operand on the stack, with the precedence of its outermost operator
+        let mut stack: Vec<(String, i32)> = Vec::with_capacity(self.max_stack_depth);
+
+        for node in &self.nodes {
+            let operand = match node {
+                Node::Variable(name) => (name.clone(), ATOM_PRECEDENCE),
+                Node::Literal(value) => literal(*value),
+                Node::Unary(operator) => {
+                    let (operand, operand_precedence) = stack.pop().unwrap();
+                    let precedence = precedence::precedence(node);
+                    let operand = parenthesize(operand, operand_precedence < precedence);
+                    let infix = match operator {
+                        UnaryOperator::Not => format!("not {operand}"),
+                        UnaryOperator::Neg => format!("-{operand}"),
+                        UnaryOperator::Plus => format!("+{operand}"),
+                    };
+                    (infix, precedence)
+                }
+                Node::Operator(operator) => {
+                    let (right, right_precedence) = stack.pop().unwrap();
+                    let (left, left_precedence) = stack.pop().unwrap();
+                    let precedence = precedence::precedence(node);
+                    // Operators of the same precedence group to the left, except the right associative ones
+                    let right_associative = precedence::is_right_associative(node);
+                    let left = parenthesize(
+                        left,
+                        left_precedence < precedence || (left_precedence == precedence && right_associative),
+                    );
+                    let right = parenthesize(
+                        right,
+                        right_precedence < precedence || (right_precedence == precedence && !right_associative),
+                    );
+                    (format!("{left} {} {right}", operator.symbol()), precedence)
+                }
+                Node::Function(function) => {
+                    let args = stack.split_off(stack.len() - function.arity());
+                    let args = args.into_iter().map(|(arg, _)| arg).collect::<Vec<_>>();
+                    (format!("{function}({})", args.join(", ")), ATOM_PRECEDENCE)
+                }
+                Node::LeftParens | Node::RightParens | Node::Separator => unreachable!("not part of postfix"),
+            };
+            stack.push(operand);
+        }
+
+        f.write_str(&stack.pop().unwrap().0)
+    }
+}
+
+fn literal(value: f64) -> (String, i32) {
+    let division = precedence::precedence(&Node::Operator(Operator::Div));
+    match value {
+        _ if value.is_nan() => ("0 / 0".to_string(), division),
+        f64::INFINITY => ("1 / 0".to_string(), division),
+        f64::NEG_INFINITY => ("-1 / 0".to_string(), division),
+        // Parses as a negation, which `-2 ^ x` would apply after the power
+        _ if value.is_sign_negative() => (format!("-{}", number(-value)), SIGN_PRECEDENCE),
+        _ => (number(value), ATOM_PRECEDENCE),
+    }
+}
+
+/// Digits that parse back into the same value, in scientific notation when they would run long, e.g. `1e300`
+fn number(value: f64) -> String {
+    const MAX_DECIMAL_LENGTH: usize = 16;
+
+    let decimal = value.to_string();
+    let scientific = format!("{value:e}");
+    if decimal.len() > MAX_DECIMAL_LENGTH && scientific.len() < decimal.len() {
+        scientific
+    } else {
+        decimal
+    }
+}
+
+fn parenthesize(infix: String, parens: bool) -> String {
+    if parens {
+        format!("({infix})")
+    } else {
+        infix
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serialization {
+    use std::fmt;
+
+    use serde::de::{Error, MapAccess, Visitor};
+    use serde::ser::SerializeStruct;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::Expression;
+
+    const FIELDS: &[&str] = &["formula", "variables"];
+
+    /// Stored as the formula, see `Display`, or as the formula and its variables when the formula doesn't name
+    /// all of them in order, e.g. after `optimize`
+    impl Serialize for Expression {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut variables = Vec::new();
+            Expression::operands(&self.nodes, &mut variables);
+            if variables == self.variables {
+                return serializer.collect_str(self);
+            }
+
+            let mut state = serializer.serialize_struct("Expression", FIELDS.len())?;
+            state.serialize_field("formula", &self.to_string())?;
+            state.serialize_field("variables", &self.variables)?;
+            state.end()
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Expression {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(ExpressionVisitor)
+        }
+    }
+
+    struct ExpressionVisitor;
+
+    impl<'de> Visitor<'de> for ExpressionVisitor {
+        type Value = Expression;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a formula, or a map with the formula and its variables")
+        }
+
+        fn visit_str<E: Error>(self, source: &str) -> Result<Expression, E> {
+            Expression::parse(source).map_err(E::custom)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Expression, A::Error> {
+            let mut formula: Option<String> = None;
+            let mut variables: Option<Vec<String>> = None;
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "formula" => formula = Some(map.next_value()?),
+                    "variables" => variables = Some(map.next_value()?),
+                    _ => return Err(A::Error::unknown_field(&key, FIELDS)),
+                }
+            }
+            let formula = formula.ok_or_else(|| A::Error::missing_field("formula"))?;
+            let mut variables = variables.ok_or_else(|| A::Error::missing_field("variables"))?;
+
+            let mut expression = Expression::parse(&formula).map_err(A::Error::custom)?;
+            if let Some(i) = (0..variables.len()).find(|&i| variables[..i].contains(&variables[i])) {
+                return Err(A::Error::custom(format!("variable '{}' is listed twice", variables[i])));
+            }
+            let listed = variables.len();
+            expression.operands = Expression::operands(&expression.nodes, &mut variables);
+            if let Some(missing) = variables.get(listed) {
+                return Err(A::Error::custom(format!(
+                    "variable '{missing}' of the formula isn't listed"
+                )));
+            }
+            expression.variables = variables;
+            Ok(expression)
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::engine::scalar::{from_bool, truthy};
+use crate::engine::EvaluationError;
+use crate::{Expression, Function, Node, Operator, UnaryOperator};
+
+/// A compiled subexpression, which computes its value for one row of input
+type Compiled = Box<dyn Fn(&[f64]) -> f64 + Send + Sync>;
+
+/// Evaluates a single row like `scalar::Engine`, through closures that the expression is compiled into up front.
+pub struct Engine {
+    expression: Expression,
+    /// The first window function instead, which can't be evaluated one row at a time
+    root: Result<Compiled, Function>,
+}
+
+/// An operand on the compile stack, variables and literals are read directly instead of through a closure.
+enum Operand {
+    Variable(usize),
+    Literal(f64),
+    Compiled(Compiled),
+}
+
+impl Operand {
+    fn compile(self) -> Compiled {
+        match self {
+            Operand::Variable(i) => Box::new(move |input| input[i]),
+            Operand::Literal(value) => Box::new(move |_| value),
+            Operand::Compiled(compiled) => compiled,
+        }
+    }
+}
+
+impl Engine {
+    pub(crate) fn new(expression: Expression) -> Self {
+        let root = Self::compile(&expression);
+        Self { expression, root }
+    }
+
+    fn compile(expression: &Expression) -> Result<Compiled, Function> {
+        let mut stack: Vec<Operand> = Vec::with_capacity(expression.max_stack_depth);
+
+        let mut operands = expression.operands.iter();
+        for node in &expression.nodes {
+            let operand = match node {
+                Node::Variable(_) => Operand::Variable(*operands.next().unwrap()),
+                Node::Literal(value) => Operand::Literal(*value),
+                Node::Unary(operator) => {
+                    let x = stack.pop().unwrap();
+                    match operator {
+                        UnaryOperator::Plus => x,
+                        UnaryOperator::Neg => unary(x, |x| -x),
+                        UnaryOperator::Not => unary(x, |x| from_bool(!truthy(x))),
+                    }
+                }
+                Node::Operator(operator) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    match operator {
+                        Operator::Add => binary(left, right, |l, r| l + r),
+                        Operator::Sub => binary(left, right, |l, r| l - r),
+                        Operator::Mul => binary(left, right, |l, r| l * r),
+                        Operator::Div => binary(left, right, |l, r| l / r),
+                        Operator::Pow => binary(left, right, f64::powf),
+                        Operator::Eq => binary(left, right, |l, r| from_bool(l == r)),
+                        Operator::Ne => binary(left, right, |l, r| from_bool(l != r)),
+                        Operator::Lt => binary(left, right, |l, r| from_bool(l < r)),
+                        Operator::Le => binary(left, right, |l, r| from_bool(l <= r)),
+                        Operator::Gt => binary(left, right, |l, r| from_bool(l > r)),
+                        Operator::Ge => binary(left, right, |l, r| from_bool(l >= r)),
+                        // The right side is only evaluated when the left side doesn't decide the result
+                        Operator::And => {
+                            let (left, right) = (left.compile(), right.compile());
+                            Operand::Compiled(Box::new(move |input| {
+                                from_bool(truthy(left(input)) && truthy(right(input)))
+                            }))
+                        }
+                        Operator::Or => {
+                            let (left, right) = (left.compile(), right.compile());
+                            Operand::Compiled(Box::new(move |input| {
+                                from_bool(truthy(left(input)) || truthy(right(input)))
+                            }))
+                        }
+                    }
+                }
+                Node::Function(function) => {
+                    let mut args = stack.split_off(stack.len() - function.arity()).into_iter();
+                    let mut arg = || args.next().unwrap();
+                    match function {
+                        Function::Min => binary(arg(), arg(), f64::min),
+                        Function::Max => binary(arg(), arg(), f64::max),
+                        Function::Abs => unary(arg(), f64::abs),
+                        Function::Sqrt => unary(arg(), f64::sqrt),
+                        Function::Exp => unary(arg(), f64::exp),
+                        Function::Ln => unary(arg(), f64::ln),
+                        // Not `f64::clamp`, which panics when `lo > hi`
+                        Function::Clamp => {
+                            let (x, lo, hi) = (arg().compile(), arg().compile(), arg().compile());
+                            Operand::Compiled(Box::new(move |input| {
+                                let x = x(input);
+                                if x.is_nan() {
+                                    x
+                                } else {
+                                    x.max(lo(input)).min(hi(input))
+                                }
+                            }))
+                        }
+                        // Only the chosen branch is evaluated
+                        Function::If => {
+                            let (condition, then, otherwise) = (arg().compile(), arg().compile(), arg().compile());
+                            Operand::Compiled(Box::new(move |input| {
+                                if truthy(condition(input)) {
+                                    then(input)
+                                } else {
+                                    otherwise(input)
+                                }
+                            }))
+                        }
+                        // Values are never null here, see `vectorized::Engine::evaluate_nullable`
+                        Function::Coalesce => arg(),
+                        Function::IsNull => Operand::Literal(0.0),
+                        Function::Lag | Function::Lead | Function::RollingSum | Function::RollingMean => {
+                            return Err(*function)
+                        }
+                    }
+                }
+                _ => unreachable!("not part of postfix"),
+            };
+            stack.push(operand);
+        }
+
+        debug_assert!(stack.len() == 1);
+        Ok(stack.pop().unwrap().compile())
+    }
+
+    /// Evaluates a single row, `input` is ordered like `Expression::variables`
+    pub fn evaluate(&self, input: &[f64]) -> Result<f64, EvaluationError> {
+        if input.len() != self.expression.required_input_length() {
+            return Err(EvaluationError::InvalidInputLength(
+                input.len(),
+                self.expression.required_input_length(),
+            ));
+        }
+
+        match &self.root {
+            Ok(root) => Ok(root(input)),
+            Err(function) => Err(EvaluationError::UnsupportedWindow(*function)),
+        }
+    }
+
+    /// Evaluates a single row where inputs are looked up by variable name
+    pub fn evaluate_named(&self, input: &[(&str, f64)]) -> Result<f64, EvaluationError> {
+        let input = self.expression.resolve(input)?;
+        self.evaluate(&input)
+    }
+}
+
+fn unary(x: Operand, op: impl Fn(f64) -> f64 + Send + Sync + 'static) -> Operand {
+    match x {
+        Operand::Variable(i) => Operand::Compiled(Box::new(move |input| op(input[i]))),
+        Operand::Literal(value) => Operand::Literal(op(value)),
+        Operand::Compiled(x) => Operand::Compiled(Box::new(move |input| op(x(input)))),
+    }
+}
+
+/// Operations on literals only are computed right away, the same way they would be at run time
+fn binary(left: Operand, right: Operand, op: impl Fn(f64, f64) -> f64 + Send + Sync + 'static) -> Operand {
+    let compiled: Compiled = match (left, right) {
+        (Operand::Literal(l), Operand::Literal(r)) => return Operand::Literal(op(l, r)),
+        (Operand::Variable(l), Operand::Variable(r)) => Box::new(move |input| op(input[l], input[r])),
+        (Operand::Variable(l), Operand::Literal(r)) => Box::new(move |input| op(input[l], r)),
+        (Operand::Literal(l), Operand::Variable(r)) => Box::new(move |input| op(l, input[r])),
+        (left, Operand::Variable(r)) => {
+            let left = left.compile();
+            Box::new(move |input| op(left(input), input[r]))
+        }
+        (left, Operand::Literal(r)) => {
+            let left = left.compile();
+            Box::new(move |input| op(left(input), r))
+        }
+        (left, right) => {
+            let (left, right) = (left.compile(), right.compile());
+            Box::new(move |input| op(left(input), right(input)))
+        }
+    };
+    Operand::Compiled(compiled)
+}
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+use crate::{Expression, Function, Node, Operator, UnaryOperator};
+
+use super::element::Element;
+use super::plan::{self, Guard, Op};
+
+/// A value in the DAG, operations refer to their arguments by index
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Value {
+    Input(usize),
+    /// Stored as `Element::to_bits` so that every literal, including NaN, equals itself
+    Literal(u64),
+    Apply(Op, Vec<usize>),
+}
+
+/// Postfix nodes turned into a DAG where identical subtrees are the same value.
+/// Values are in topological order, every argument comes before the values that use it.
+#[derive(Clone, Debug)]
+pub(crate) struct Dag<T = f64> {
+    pub(crate) values: Vec<Value>,
+    /// Rows where an operation that can fail is used, see `add_nullable` and `guard_branches`
+    pub(crate) guards: HashMap<usize, Guard<usize>>,
+    /// `and` and `or` that only use their right side where the left side doesn't decide the result
+    short_circuits: HashSet<usize>,
+    ids: HashMap<Value, usize>,
+    element: PhantomData<T>,
+}
+
+impl<T> Default for Dag<T> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            guards: HashMap::new(),
+            short_circuits: HashSet::new(),
+            ids: HashMap::new(),
+            element: PhantomData,
+        }
+    }
+}
+
+impl<T: Element> Dag<T> {
+    /// Adds the nodes of `expression`, folding operations on literals, and returns the id of its result.
+    /// Literals must be representable as `T`, see `element::check`.
+    pub(crate) fn add(&mut self, expression: &Expression) -> usize {
+        if expression.has_windows() {
+            let (value, validity) = self.add_nullable(expression, &vec![None; expression.required_input_length()]);
+            let null = T::from_f64(f64::NAN).unwrap_or_default();
+            let null = self.intern(Value::Literal(null.to_bits()));
+            return self.select(validity, value, null);
+        }
+
+        let mut stack: Vec<usize> = Vec::with_capacity(expression.max_stack_depth);
+
+        let mut operands = expression.operands.iter();
+        for node in &expression.nodes {
+            let id = match node {
+                Node::Variable(_) => self.intern(Value::Input(*operands.next().unwrap())),
+                Node::Literal(value) => self.constant(*value),
+                // Nothing is null without validity
+                Node::Function(Function::Coalesce) => stack.split_off(stack.len() - 2)[0],
+                Node::Function(Function::IsNull) => {
+                    stack.pop();
+                    self.constant(0.0)
+                }
+                node => {
+                    let args = stack.split_off(stack.len() - node.arity());
+                    let id = self.apply(Op::from_node(node).unwrap(), args);
+                    if matches!(node, Node::Operator(Operator::And | Operator::Or)) {
+                        self.short_circuits.insert(id);
+                    }
+                    id
+                }
+            };
+            stack.push(id);
+        }
+
+        debug_assert!(stack.len() == 1);
+        stack.pop().unwrap()
+    }
+
+    /// Same as `add`, but also adds the validity of the result, 1 or 0, with nulls propagating like in SQL.
+    /// `validity` has the input holding the validity of each input column, `None` when it is never null.
+    pub(crate) fn add_nullable(&mut self, expression: &Expression, validity: &[Option<usize>]) -> (usize, usize) {
+        // (value, validity) of every operand on the stack
+        let mut stack: Vec<(usize, usize)> = Vec::with_capacity(expression.max_stack_depth);
+
+        let valid = self.constant(1.0);
+        let mut operands = expression.operands.iter();
+        for node in &expression.nodes {
+            let (value, validity) = match node {
+                Node::Variable(_) => {
+                    let i = *operands.next().unwrap();
+                    let validity = validity[i].map_or(valid, |j| self.intern(Value::Input(j)));
+                    (self.intern(Value::Input(i)), validity)
+                }
+                Node::Literal(value) => (self.constant(*value), valid),
+                node => {
+                    let (args, valids): (Vec<_>, Vec<_>) =
+                        stack.split_off(stack.len() - node.arity()).into_iter().unzip();
+                    match (node, &args[..], &valids[..]) {
+                        (Node::Function(Function::Coalesce), &[x, fallback], &[vx, vfallback]) => {
+                            (self.select(vx, x, fallback), self.or(vx, vfallback))
+                        }
+                        (Node::Function(Function::IsNull), _, &[vx]) => (self.not(vx), valid),
+                        // Windows are computed for every row, then rows where the window reaches past the first
+                        // or last row are 0 in both the value and its validity
+                        (&Node::Function(function), &[x, size], &[vx, _]) if function.is_window() => {
+                            let size = self.literal(size).unwrap().to_f64() as usize;
+                            let value = self.intern(Value::Apply(Op::Window(function, size), vec![x]));
+                            let validity = match function {
+                                Function::Lag | Function::Lead => {
+                                    self.intern(Value::Apply(Op::Window(function, size), vec![vx]))
+                                }
+                                // Only valid when all of the rows are, validity may be any truthy value
+                                _ => {
+                                    let zero = self.constant(0.0);
+                                    let vx = self.apply(Op::Binary(Operator::Ne), vec![vx, zero]);
+                                    let valid_rows =
+                                        self.intern(Value::Apply(Op::Window(Function::RollingSum, size), vec![vx]));
+                                    let size = self.constant(size as f64);
+                                    self.apply(Op::Binary(Operator::Eq), vec![valid_rows, size])
+                                }
+                            };
+                            (value, validity)
+                        }
+                        (Node::Function(Function::If), &[c, a, b], &[vc, va, vb]) => {
+                            let c = self.and(vc, c);
+                            (self.select(c, a, b), self.select(c, va, vb))
+                        }
+                        // Valid when both sides are, or when either side alone decides the result
+                        (Node::Operator(Operator::And), &[l, r], &[vl, vr]) => {
+                            let (false_l, false_r) = (self.not(l), self.not(r));
+                            let decided = [self.and(vl, vr), self.and(vl, false_l), self.and(vr, false_r)];
+                            let validity = decided.into_iter().reduce(|a, b| self.or(a, b)).unwrap();
+                            (self.apply(Op::Binary(Operator::And), vec![l, r]), validity)
+                        }
+                        (Node::Operator(Operator::Or), &[l, r], &[vl, vr]) => {
+                            let decided = [self.and(vl, vr), self.and(vl, l), self.and(vr, r)];
+                            let validity = decided.into_iter().reduce(|a, b| self.or(a, b)).unwrap();
+                            (self.apply(Op::Binary(Operator::Or), vec![l, r]), validity)
+                        }
+                        _ => {
+                            let validity = valids.into_iter().reduce(|a, b| self.and(a, b)).unwrap();
+                            let value = self.apply(Op::from_node(node).unwrap(), args);
+                            // Null rows hold arbitrary values, which mustn't report an overflow.
+                            // Values older than their validity are logical operations, which never overflow
+                            if value > validity && self.literal(validity).is_none() {
+                                self.guards.entry(value).or_insert(vec![vec![(validity, true)]]);
+                            }
+                            (value, validity)
+                        }
+                    }
+                }
+            };
+            stack.push((value, validity));
+        }
+
+        debug_assert!(stack.len() == 1);
+        stack.pop().unwrap()
+    }
+
+    /// The operation on `args`, folded into a literal when it only has literal arguments
+    fn apply(&mut self, op: Op, mut args: Vec<usize>) -> usize {
+        let op = match (op, self.literal(args[args.len() - 1])) {
+            (Op::Binary(Operator::Pow), Some(exponent)) => match plan::integer_exponent(exponent.to_f64()) {
+                Some(n) => {
+                    args.pop();
+                    Op::Powi(n)
+                }
+                None => op,
+            },
+            _ => op,
+        };
+
+        let literals = args.iter().map_while(|&arg| self.literal(arg)).collect::<Vec<_>>();
+        let folded = if literals.len() == args.len() {
+            plan::fold(op, &literals)
+        } else {
+            None
+        };
+        match folded {
+            Some(value) => self.intern(Value::Literal(value.to_bits())),
+            None => self.intern(Value::Apply(op, args)),
+        }
+    }
+
+    fn constant(&mut self, value: f64) -> usize {
+        let value = T::from_f64(value).expect("literals have been type checked");
+        self.intern(Value::Literal(value.to_bits()))
+    }
+
+    /// Whether the value is a literal that is true, `None` when it isn't a literal
+    fn truthy(&self, id: usize) -> Option<bool> {
+        self.literal(id).map(|value| value != T::default())
+    }
+
+    // Logical operations for validity, which skip literal operands instead of computing e.g. `x and 1`.
+    // The results are only ever used as conditions, so `x` stands in for `x and 1` even when it isn't 0 or 1.
+
+    fn and(&mut self, l: usize, r: usize) -> usize {
+        match (self.truthy(l), self.truthy(r)) {
+            (Some(false), _) => l,
+            (_, Some(false)) => r,
+            (Some(true), _) => r,
+            (_, Some(true)) => l,
+            _ => self.apply(Op::Binary(Operator::And), vec![l, r]),
+        }
+    }
+
+    fn or(&mut self, l: usize, r: usize) -> usize {
+        match (self.truthy(l), self.truthy(r)) {
+            (Some(true), _) => l,
+            (_, Some(true)) => r,
+            (Some(false), _) => r,
+            (_, Some(false)) => l,
+            _ => self.apply(Op::Binary(Operator::Or), vec![l, r]),
+        }
+    }
+
+    fn not(&mut self, x: usize) -> usize {
+        self.apply(Op::Unary(UnaryOperator::Not), vec![x])
+    }
+
+    fn select(&mut self, condition: usize, then: usize, otherwise: usize) -> usize {
+        match self.truthy(condition) {
+            _ if then == otherwise => then,
+            Some(true) => then,
+            Some(false) => otherwise,
+            None => self.apply(Op::Function(Function::If), vec![condition, then, otherwise]),
+        }
+    }
+
+    /// Copies the values that `roots` depend on into a new DAG, reading those that `column` maps from input columns.
+    pub(crate) fn extract(&self, roots: &[usize], column: impl Fn(usize) -> Option<usize>) -> (Dag<T>, Vec<usize>) {
+        // Every argument comes before the values that use it, so marking what is needed goes backwards
+        let mut needed = vec![false; self.values.len()];
+        for &root in roots {
+            needed[root] = true;
+        }
+        for id in (0..self.values.len()).rev() {
+            if !needed[id] || column(id).is_some() {
+                continue;
+            }
+            if let Value::Apply(_, args) = &self.values[id] {
+                for &arg in args.iter().chain(&self.guard_values(id)) {
+                    needed[arg] = true;
+                }
+            }
+        }
+
+        let mut dag = Dag::default();
+        let mut ids: Vec<Option<usize>> = vec![None; self.values.len()];
+        for (id, value) in self.values.iter().enumerate() {
+            if !needed[id] {
+                continue;
+            }
+            let new = match (column(id), value) {
+                (Some(i), _) => dag.intern(Value::Input(i)),
+                (None, Value::Apply(op, args)) => {
+                    let new = dag.intern(Value::Apply(*op, args.iter().map(|&arg| ids[arg].unwrap()).collect()));
+                    if let Some(guard) = self.guards.get(&id) {
+                        let conjunction = |all: &Vec<(usize, bool)>| {
+                            all.iter().map(|&(id, truthy)| (ids[id].unwrap(), truthy)).collect()
+                        };
+                        dag.guards.insert(new, guard.iter().map(conjunction).collect());
+                    }
+                    new
+                }
+                (None, value) => dag.intern(value.clone()),
+            };
+            ids[id] = Some(new);
+        }
+
+        (dag, roots.iter().map(|&root| ids[root].unwrap()).collect())
+    }
+
+    /// Only checks operations that can fail in the rows where the scalar engine would evaluate them,
+    /// e.g. in the branch of an `if` that is taken. Conditions that come after the operation are left out.
+    pub(crate) fn guard_branches(&mut self, roots: &[usize]) {
+        // Rows where each value is used, `None` where it isn't
+        let mut uses: Vec<Option<Guard<usize>>> = vec![None; self.values.len()];
+        for &root in roots {
+            uses[root] = Some(vec![Vec::new()]);
+        }
+
+        for id in (0..self.values.len()).rev() {
+            let (Some(used), Value::Apply(op, args)) = (uses[id].clone(), &self.values[id]) else {
+                continue;
+            };
+            for (k, &arg) in args.iter().enumerate() {
+                let condition = match (op, k) {
+                    (Op::Function(Function::If), 1) => Some((args[0], true)),
+                    (Op::Function(Function::If), 2) => Some((args[0], false)),
+                    (Op::Binary(Operator::And), 1) if self.short_circuits.contains(&id) => Some((args[0], true)),
+                    (Op::Binary(Operator::Or), 1) if self.short_circuits.contains(&id) => Some((args[0], false)),
+                    _ => None,
+                };
+                // Windows read other rows than their own
+                let used = match op {
+                    Op::Window(..) => vec![Vec::new()],
+                    _ => and(used.clone(), condition),
+                };
+                uses[arg] = Some(match uses[arg].take() {
+                    Some(uses) => or(uses, used),
+                    None => used,
+                });
+            }
+            for arg in self.guard_values(id) {
+                uses[arg] = Some(vec![Vec::new()]);
+            }
+        }
+
+        for (id, used) in uses.into_iter().enumerate() {
+            let (Some(used), Value::Apply(op, _)) = (used, &self.values[id]) else {
+                continue;
+            };
+            let used = used
+                .into_iter()
+                .map(|all| {
+                    all.into_iter()
+                        .filter(|&(condition, _)| condition < id)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            if !op.can_fail() || used.iter().any(Vec::is_empty) {
+                continue;
+            }
+
+            let guard = match self.guards.remove(&id) {
+                Some(nulls) => nulls
+                    .iter()
+                    .flat_map(|valid| used.iter().map(|all| [valid.clone(), all.clone()].concat()))
+                    .collect(),
+                None => used,
+            };
+            self.guards.insert(id, guard);
+        }
+    }
+
+    /// Values that the guard of an operation reads
+    pub(crate) fn guard_values(&self, id: usize) -> Vec<usize> {
+        let guard = self.guards.get(&id).into_iter().flatten();
+        guard.flatten().map(|&(id, _)| id).collect()
+    }
+
+    pub(crate) fn literal(&self, id: usize) -> Option<T> {
+        match self.values[id] {
+            Value::Literal(bits) => Some(T::from_bits(bits)),
+            _ => None,
+        }
+    }
+
+    fn intern(&mut self, value: Value) -> usize {
+        if let Some(&id) = self.ids.get(&value) {
+            return id;
+        }
+
+        self.values.push(value.clone());
+        self.ids.insert(value, self.values.len() - 1);
+        self.values.len() - 1
+    }
+}
+
+/// Largest number of conjunctions in a guard, beyond that every row is checked
+const MAX_CONJUNCTIONS: usize = 4;
+
+/// Rows where both `guard` holds and `condition` is truthy, or 0 when it is `false`
+fn and(guard: Guard<usize>, condition: Option<(usize, bool)>) -> Guard<usize> {
+    let Some(condition) = condition else {
+        return guard;
+    };
+    let with_condition = |mut all: Vec<(usize, bool)>| {
+        if !all.contains(&condition) {
+            all.push(condition);
+        }
+        all
+    };
+    guard.into_iter().map(with_condition).collect()
+}
+
+/// Rows where either guard holds, without conjunctions that another one already covers
+fn or(mut guard: Guard<usize>, other: Guard<usize>) -> Guard<usize> {
+    for all in other {
+        if guard
+            .iter()
+            .any(|covered| covered.iter().all(|condition| all.contains(condition)))
+        {
+            continue;
+        }
+        guard.retain(|covered| !all.iter().all(|condition| covered.contains(condition)));
+        guard.push(all);
+    }
+    if guard.len() > MAX_CONJUNCTIONS {
+        return vec![Vec::new()];
+    }
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dag(source: &str) -> (Dag, usize) {
+        let mut dag = Dag::<f64>::default();
+        let root = dag.add(&Expression::parse(source).unwrap());
+        (dag, root)
+    }
+
+    #[test]
+    fn identical_subtrees_are_shared() {
+        let (dag, root) = dag("(a - b) * (a - b) + sqrt(a - b)");
+        // a, b, a - b, *, sqrt, +
+        assert_eq!(dag.values.len(), 6);
+        assert_eq!(dag.values[2], Value::Apply(Op::Binary(Operator::Sub), vec![0, 1]));
+        assert_eq!(dag.values[3], Value::Apply(Op::Binary(Operator::Mul), vec![2, 2]));
+        assert_eq!(root, 5);
+    }
+
+    #[test]
+    fn operand_order_matters() {
+        let (dag, _) = dag("(a - b) + (b - a)");
+        assert_eq!(dag.values.len(), 5);
+    }
+
+    #[test]
+    fn folded_literals_are_shared() {
+        let (dag, root) = dag("(a - 1) * (a - (2 - 1))");
+        assert_eq!(dag.values[root], Value::Apply(Op::Binary(Operator::Mul), vec![2, 2]));
+    }
+
+    #[test]
+    fn validity_is_only_computed_for_nullable_inputs() {
+        let expression = Expression::parse("(a + b) * c").unwrap();
+        let mut dag = Dag::<f64>::default();
+        let (_, validity) = dag.add_nullable(&expression, &[None, None, None]);
+        assert_eq!(dag.literal(validity), Some(1.0));
+
+        let mut dag = Dag::<f64>::default();
+        let (value, validity) = dag.add_nullable(&expression, &[None, Some(3), Some(4)]);
+        let (b, c) = (dag.ids[&Value::Input(3)], dag.ids[&Value::Input(4)]);
+        assert_eq!(
+            dag.values[validity],
+            Value::Apply(Op::Binary(Operator::And), vec![b, c])
+        );
+        assert_eq!(dag.guards[&value], [[(validity, true)]]);
+    }
+}
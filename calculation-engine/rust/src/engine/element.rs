@@ -0,0 +1,544 @@
+use std::array;
+use std::fmt::{self, Debug, Display};
+use std::simd::cmp::{SimdOrd, SimdPartialEq, SimdPartialOrd};
+use std::simd::num::{SimdFloat, SimdInt};
+use std::simd::{Select, Simd, SimdElement, StdFloat};
+
+use crate::{Expression, ExpressionConstructionError, Function, Node, Operator};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ElementType {
+    F32,
+    F64,
+    I64,
+}
+
+impl Display for ElementType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ElementType::F32 => "f32",
+            ElementType::F64 => "f64",
+            ElementType::I64 => "i64",
+        })
+    }
+}
+
+/// What happens when integer arithmetic overflows, or divides by zero.
+/// Floating point types follow IEEE 754 and never overflow.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Overflow {
+    /// Evaluation stops with `EvaluationError::Overflow`
+    #[default]
+    Checked,
+    /// Results wrap around, division by zero results in 0
+    Wrapping,
+}
+
+/// What happens when a floating point operation turns finite arguments into NaN or infinity, e.g. `sqrt(-1)`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum FloatPolicy {
+    /// The result is NaN or infinity, following IEEE 754
+    #[default]
+    Propagate,
+    /// Evaluation stops with `EvaluationError::NonFinite`
+    Error,
+    /// The result is this value instead
+    Replace(f64),
+}
+
+/// Column element type the vectorized engine can evaluate expressions over, `N` lanes at a time.
+/// Operations that overflow set `overflow` and wrap, comparisons and logic return 1 or 0.
+pub trait Element: SimdElement + Copy + PartialEq + Default + Debug + Send + Sync + 'static {
+    const TYPE: ElementType;
+
+    /// Converts a literal, `None` when this type can't represent it
+    fn from_f64(value: f64) -> Option<Self>;
+    fn to_f64(self) -> f64;
+    /// Bit pattern that identifies the value, so that literals can be compared and hashed
+    fn to_bits(self) -> u64;
+    fn from_bits(bits: u64) -> Self;
+    /// Lane-wise `to_f64`, reductions accumulate in `f64`
+    fn widen<const N: usize>(x: Simd<Self, N>) -> Simd<f64, N>;
+    /// The lanes of an integer type, which are summed exactly, see `Reduction::Sum`
+    fn integers<const N: usize>(x: Simd<Self, N>) -> Option<Simd<i64, N>>;
+
+    fn add<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+    fn sub<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+    fn mul<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+    fn div<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+    fn pow<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+    /// `x ^ n` for a small constant `n`
+    fn powi<const N: usize>(x: Simd<Self, N>, n: i32, overflow: &mut bool) -> Simd<Self, N>;
+    fn neg<const N: usize>(x: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+    fn abs<const N: usize>(x: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N>;
+
+    fn min<const N: usize>(a: Simd<Self, N>, b: Simd<Self, N>) -> Simd<Self, N>;
+    fn max<const N: usize>(a: Simd<Self, N>, b: Simd<Self, N>) -> Simd<Self, N>;
+    /// Same as `min(max(x, lo), hi)`, except that NaN stays NaN
+    fn clamp<const N: usize>(x: Simd<Self, N>, lo: Simd<Self, N>, hi: Simd<Self, N>) -> Simd<Self, N>;
+    /// Only called for floating point types, see `supports`
+    fn sqrt<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N>;
+    fn exp<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N>;
+    fn ln<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N>;
+
+    fn equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn not_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn less<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn less_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn greater<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn greater_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn not<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N>;
+    fn and<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    fn or<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N>;
+    /// `if(condition, then, otherwise)`, both branches have been evaluated for every lane
+    fn select<const N: usize>(condition: Simd<Self, N>, then: Simd<Self, N>, otherwise: Simd<Self, N>)
+        -> Simd<Self, N>;
+
+    /// Whether any lane of `result` isn't finite although the same lane of every argument is,
+    /// and if so replaces those lanes with `replacement` when there is one
+    fn non_finite<const N: usize, const A: usize>(
+        args: &[Simd<Self, N>; A],
+        result: &mut Simd<Self, N>,
+        replacement: Option<Self>,
+    ) -> bool;
+
+    fn supports(function: Function) -> bool;
+}
+
+macro_rules! float_element {
+    ($t:ty, $bits:ty, $element:expr) => {
+        impl Element for $t {
+            const TYPE: ElementType = $element;
+
+            fn from_f64(value: f64) -> Option<Self> {
+                // Finite literals beyond the range of `f32` would become infinite
+                let converted = value as $t;
+                (converted.is_finite() || !value.is_finite()).then_some(converted)
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn to_bits(self) -> u64 {
+                <$t>::to_bits(self) as u64
+            }
+
+            fn from_bits(bits: u64) -> Self {
+                <$t>::from_bits(bits as $bits)
+            }
+
+            #[inline(always)]
+            fn widen<const N: usize>(x: Simd<Self, N>) -> Simd<f64, N> {
+                x.cast()
+            }
+
+            #[inline(always)]
+            fn integers<const N: usize>(_: Simd<Self, N>) -> Option<Simd<i64, N>> {
+                None
+            }
+
+            #[inline(always)]
+            fn add<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                l + r
+            }
+
+            #[inline(always)]
+            fn sub<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                l - r
+            }
+
+            #[inline(always)]
+            fn mul<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                l * r
+            }
+
+            #[inline(always)]
+            fn div<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                l / r
+            }
+
+            #[inline(always)]
+            fn pow<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                Simd::from_array(array::from_fn(|k| l[k].powf(r[k])))
+            }
+
+            #[inline(always)]
+            fn powi<const N: usize>(x: Simd<Self, N>, n: i32, _: &mut bool) -> Simd<Self, N> {
+                let one = Simd::splat(1.0);
+                let mut result = one;
+                let mut base_pow = x;
+                let mut e = n.unsigned_abs();
+                while e > 0 {
+                    if e & 1 == 1 {
+                        result *= base_pow;
+                    }
+                    base_pow *= base_pow;
+                    e >>= 1;
+                }
+
+                if n < 0 {
+                    one / result
+                } else {
+                    result
+                }
+            }
+
+            #[inline(always)]
+            fn neg<const N: usize>(x: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                -x
+            }
+
+            #[inline(always)]
+            fn abs<const N: usize>(x: Simd<Self, N>, _: &mut bool) -> Simd<Self, N> {
+                x.abs()
+            }
+
+            #[inline(always)]
+            fn min<const N: usize>(a: Simd<Self, N>, b: Simd<Self, N>) -> Simd<Self, N> {
+                a.simd_min(b)
+            }
+
+            #[inline(always)]
+            fn max<const N: usize>(a: Simd<Self, N>, b: Simd<Self, N>) -> Simd<Self, N> {
+                a.simd_max(b)
+            }
+
+            // Not `simd_clamp`, which panics when `lo > hi`
+            #[inline(always)]
+            fn clamp<const N: usize>(x: Simd<Self, N>, lo: Simd<Self, N>, hi: Simd<Self, N>) -> Simd<Self, N> {
+                x.is_nan().select(x, x.simd_max(lo).simd_min(hi))
+            }
+
+            #[inline(always)]
+            fn sqrt<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N> {
+                x.sqrt()
+            }
+
+            #[inline(always)]
+            fn exp<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N> {
+                x.exp()
+            }
+
+            #[inline(always)]
+            fn ln<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N> {
+                x.ln()
+            }
+
+            #[inline(always)]
+            fn equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                l.simd_eq(r).select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn not_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                l.simd_ne(r).select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn less<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                l.simd_lt(r).select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn less_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                l.simd_le(r).select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn greater<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                l.simd_gt(r).select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn greater_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                l.simd_ge(r).select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn not<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N> {
+                x.simd_eq(Simd::splat(0.0))
+                    .select(Simd::splat(1.0), Simd::splat(0.0))
+            }
+
+            #[inline(always)]
+            fn and<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                let zero = Simd::splat(0.0);
+                (l.simd_ne(zero) & r.simd_ne(zero)).select(Simd::splat(1.0), zero)
+            }
+
+            #[inline(always)]
+            fn or<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+                let zero = Simd::splat(0.0);
+                (l.simd_ne(zero) | r.simd_ne(zero)).select(Simd::splat(1.0), zero)
+            }
+
+            #[inline(always)]
+            fn select<const N: usize>(
+                condition: Simd<Self, N>,
+                then: Simd<Self, N>,
+                otherwise: Simd<Self, N>,
+            ) -> Simd<Self, N> {
+                condition.simd_ne(Simd::splat(0.0)).select(then, otherwise)
+            }
+
+            #[inline(always)]
+            fn non_finite<const N: usize, const A: usize>(
+                args: &[Simd<Self, N>; A],
+                result: &mut Simd<Self, N>,
+                replacement: Option<Self>,
+            ) -> bool {
+                let finite = args
+                    .iter()
+                    .fold(result.is_finite(), |finite, arg| finite | !arg.is_finite());
+                if finite.all() {
+                    return false;
+                }
+                if let Some(replacement) = replacement {
+                    *result = finite.select(*result, Simd::splat(replacement));
+                }
+                true
+            }
+
+            fn supports(_: Function) -> bool {
+                true
+            }
+        }
+    };
+}
+
+float_element!(f64, u64, ElementType::F64);
+float_element!(f32, u32, ElementType::F32);
+
+/// Lane-wise operation on integers that reports whether any lane overflowed
+#[inline(always)]
+fn overflowing<const N: usize>(
+    l: Simd<i64, N>,
+    r: Simd<i64, N>,
+    overflow: &mut bool,
+    op: impl Fn(i64, i64) -> (i64, bool),
+) -> Simd<i64, N> {
+    let mut any = false;
+    let result = array::from_fn(|k| {
+        let (value, lane) = op(l[k], r[k]);
+        any |= lane;
+        value
+    });
+    *overflow |= any;
+    Simd::from_array(result)
+}
+
+/// Exponents are non-negative, see `check`. Exponents beyond `u32` keep their parity,
+/// so that `-1 ^ n` is still right and anything else overflows.
+#[inline(always)]
+fn overflowing_pow(x: i64, n: i64) -> (i64, bool) {
+    let n = u32::try_from(n).unwrap_or(u32::MAX - 1 + (n & 1) as u32);
+    x.overflowing_pow(n)
+}
+
+impl Element for i64 {
+    const TYPE: ElementType = ElementType::I64;
+
+    fn from_f64(value: f64) -> Option<Self> {
+        // `i64::MAX as f64` rounds up to 2^63, which is out of range
+        let in_range = value >= i64::MIN as f64 && value < i64::MAX as f64;
+        (value.fract() == 0.0 && in_range).then_some(value as i64)
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        bits as i64
+    }
+
+    #[inline(always)]
+    fn widen<const N: usize>(x: Simd<Self, N>) -> Simd<f64, N> {
+        x.cast()
+    }
+
+    #[inline(always)]
+    fn integers<const N: usize>(x: Simd<Self, N>) -> Option<Simd<i64, N>> {
+        Some(x)
+    }
+
+    #[inline(always)]
+    fn add<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        let sum = l + r;
+        // Overflow happened when both operands have a different sign than the result
+        *overflow |= ((l ^ sum) & (r ^ sum)).simd_lt(Simd::splat(0)).any();
+        sum
+    }
+
+    #[inline(always)]
+    fn sub<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        let difference = l - r;
+        *overflow |= ((l ^ r) & (l ^ difference)).simd_lt(Simd::splat(0)).any();
+        difference
+    }
+
+    #[inline(always)]
+    fn mul<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        overflowing(l, r, overflow, i64::overflowing_mul)
+    }
+
+    #[inline(always)]
+    fn div<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        overflowing(l, r, overflow, |l, r| match r {
+            0 => (0, true),
+            r => l.overflowing_div(r),
+        })
+    }
+
+    #[inline(always)]
+    fn pow<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        overflowing(l, r, overflow, overflowing_pow)
+    }
+
+    #[inline(always)]
+    fn powi<const N: usize>(x: Simd<Self, N>, n: i32, overflow: &mut bool) -> Simd<Self, N> {
+        overflowing(x, Simd::splat(n as i64), overflow, overflowing_pow)
+    }
+
+    #[inline(always)]
+    fn neg<const N: usize>(x: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        *overflow |= x.simd_eq(Simd::splat(i64::MIN)).any();
+        -x
+    }
+
+    #[inline(always)]
+    fn abs<const N: usize>(x: Simd<Self, N>, overflow: &mut bool) -> Simd<Self, N> {
+        *overflow |= x.simd_eq(Simd::splat(i64::MIN)).any();
+        x.abs()
+    }
+
+    #[inline(always)]
+    fn min<const N: usize>(a: Simd<Self, N>, b: Simd<Self, N>) -> Simd<Self, N> {
+        a.simd_min(b)
+    }
+
+    #[inline(always)]
+    fn max<const N: usize>(a: Simd<Self, N>, b: Simd<Self, N>) -> Simd<Self, N> {
+        a.simd_max(b)
+    }
+
+    #[inline(always)]
+    fn clamp<const N: usize>(x: Simd<Self, N>, lo: Simd<Self, N>, hi: Simd<Self, N>) -> Simd<Self, N> {
+        x.simd_max(lo).simd_min(hi)
+    }
+
+    fn sqrt<const N: usize>(_: Simd<Self, N>) -> Simd<Self, N> {
+        unreachable!("rejected by type checking")
+    }
+
+    fn exp<const N: usize>(_: Simd<Self, N>) -> Simd<Self, N> {
+        unreachable!("rejected by type checking")
+    }
+
+    fn ln<const N: usize>(_: Simd<Self, N>) -> Simd<Self, N> {
+        unreachable!("rejected by type checking")
+    }
+
+    #[inline(always)]
+    fn equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        l.simd_eq(r).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn not_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        l.simd_ne(r).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn less<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        l.simd_lt(r).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn less_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        l.simd_le(r).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn greater<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        l.simd_gt(r).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn greater_equal<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        l.simd_ge(r).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn not<const N: usize>(x: Simd<Self, N>) -> Simd<Self, N> {
+        x.simd_eq(Simd::splat(0)).select(Simd::splat(1), Simd::splat(0))
+    }
+
+    #[inline(always)]
+    fn and<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        let zero = Simd::splat(0);
+        (l.simd_ne(zero) & r.simd_ne(zero)).select(Simd::splat(1), zero)
+    }
+
+    #[inline(always)]
+    fn or<const N: usize>(l: Simd<Self, N>, r: Simd<Self, N>) -> Simd<Self, N> {
+        let zero = Simd::splat(0);
+        (l.simd_ne(zero) | r.simd_ne(zero)).select(Simd::splat(1), zero)
+    }
+
+    #[inline(always)]
+    fn select<const N: usize>(
+        condition: Simd<Self, N>,
+        then: Simd<Self, N>,
+        otherwise: Simd<Self, N>,
+    ) -> Simd<Self, N> {
+        condition.simd_ne(Simd::splat(0)).select(then, otherwise)
+    }
+
+    fn non_finite<const N: usize, const A: usize>(
+        _: &[Simd<Self, N>; A],
+        _: &mut Simd<Self, N>,
+        _: Option<Self>,
+    ) -> bool {
+        false
+    }
+
+    fn supports(function: Function) -> bool {
+        !matches!(
+            function,
+            Function::Sqrt | Function::Exp | Function::Ln | Function::RollingMean
+        )
+    }
+}
+
+/// Checks that every literal and operation of `expression` can be evaluated as `T`
+pub(crate) fn check<T: Element>(expression: &Expression) -> Result<(), ExpressionConstructionError> {
+    for (k, node) in expression.nodes.iter().enumerate() {
+        match node {
+            Node::Literal(value) if T::from_f64(*value).is_none() => {
+                return Err(ExpressionConstructionError::UnrepresentableLiteral(T::TYPE));
+            }
+            Node::Function(function) if !T::supports(*function) => {
+                return Err(ExpressionConstructionError::UnsupportedFunction {
+                    function: *function,
+                    element: T::TYPE,
+                });
+            }
+            // The exponent is the node right before, integers have no fractional or negative powers
+            Node::Operator(Operator::Pow)
+                if T::TYPE == ElementType::I64
+                    && !matches!(expression.nodes[k - 1], Node::Literal(exponent) if exponent >= 0.0) =>
+            {
+                return Err(ExpressionConstructionError::InvalidExponent(T::TYPE));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
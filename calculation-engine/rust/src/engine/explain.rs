@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{Expression, Node, UnaryOperator};
+
+use super::element::Element;
+use super::plan::{Op, Pipeline, Plan, Slot, Step};
+
+/// Time spent in one operator, summed over every step that runs it, see `vectorized::Engine::with_timings`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OperatorTiming {
+    /// Steps that ran, which is once per chunk of rows except for `evaluate`
+    pub calls: u64,
+    pub rows: u64,
+    pub time: Duration,
+}
+
+/// Timings that are collected while evaluating, by the name of the operator
+#[derive(Debug, Default)]
+pub(crate) struct Timings {
+    operators: Mutex<BTreeMap<&'static str, OperatorTiming>>,
+}
+
+impl Timings {
+    /// Runs `f`, which computes `op` for `rows` rows, and records how long it took when there are `timings`
+    #[inline(always)]
+    pub(crate) fn measure<R>(timings: Option<&Timings>, op: Op, rows: usize, f: impl FnOnce() -> R) -> R {
+        let Some(timings) = timings else {
+            return f();
+        };
+
+        let start = Instant::now();
+        let result = f();
+        let time = start.elapsed();
+
+        let mut operators = timings.operators.lock().unwrap();
+        let timing = operators.entry(name(op)).or_default();
+        timing.calls += 1;
+        timing.rows += rows as u64;
+        timing.time += time;
+        result
+    }
+
+    pub(crate) fn get(&self) -> BTreeMap<&'static str, OperatorTiming> {
+        self.operators.lock().unwrap().clone()
+    }
+
+    pub(crate) fn reset(&self) {
+        self.operators.lock().unwrap().clear();
+    }
+}
+
+/// Unlike `Op::name`, tells negation apart from subtraction, and small integer powers from `powf`
+fn name(op: Op) -> &'static str {
+    match op {
+        Op::Unary(operator) => unary_name(operator),
+        Op::Powi(_) => "powi",
+        op => op.name(),
+    }
+}
+
+fn unary_name(operator: UnaryOperator) -> &'static str {
+    match operator {
+        UnaryOperator::Plus => "pos",
+        UnaryOperator::Neg => "neg",
+        UnaryOperator::Not => "not",
+    }
+}
+
+/// Describes what the vectorized engine does for `expression`, see `vectorized::Engine::explain`
+pub(crate) fn explain<T: Element, const N: usize>(
+    expression: &Expression,
+    pipeline: &Pipeline<T>,
+    chunk_rows: usize,
+) -> String {
+    let postfix = expression
+        .nodes
+        .iter()
+        .map(|node| match node {
+            Node::Variable(name) => name.clone(),
+            Node::Literal(value) => value.to_string(),
+            Node::Unary(operator) => unary_name(*operator).to_string(),
+            Node::Operator(operator) => operator.symbol().to_string(),
+            Node::Function(function) => function.name().to_string(),
+            Node::LeftParens | Node::RightParens | Node::Separator => unreachable!("not part of postfix"),
+        })
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    writeln!(out, "postfix: {}", postfix.join(" ")).unwrap();
+    writeln!(out, "stack depth: {}", expression.max_stack_depth).unwrap();
+    let bits = N * size_of::<T>() * 8;
+    writeln!(out, "lanes: {N} x {}, {bits} bit vectors", T::TYPE).unwrap();
+    writeln!(out, "fused chunks: {chunk_rows} rows").unwrap();
+
+    let variables = expression.variables();
+    let mut windows = 0;
+    for (k, (plan, functions)) in pipeline.stages.iter().enumerate() {
+        write_plan(&mut out, &format!("stage {}", k + 1), plan, variables);
+        for (i, (function, size)) in functions.iter().enumerate() {
+            writeln!(out, "  window{windows} = {function}(out{i}, {size})").unwrap();
+            windows += 1;
+        }
+    }
+    write_plan(&mut out, "plan", &pipeline.last, variables);
+
+    out
+}
+
+fn write_plan<T: Element>(out: &mut String, title: &str, plan: &Plan<T>, variables: &[String]) {
+    let columns = plan.scratch_columns;
+    let plural = if columns == 1 { "" } else { "s" };
+    writeln!(out, "{title}: {columns} scratch column{plural}").unwrap();
+
+    let slot = |slot: &Slot<T>| match *slot {
+        Slot::Input(i) => match variables.get(i) {
+            Some(variable) => variable.clone(),
+            None => format!("window{}", i - variables.len()),
+        },
+        Slot::Literal(value) => value.to_f64().to_string(),
+        Slot::Scratch(i) => format!("scratch{i}"),
+        Slot::Output(k) => format!("out{k}"),
+    };
+
+    for Step { op, args, dst, guard } in &plan.steps {
+        let args = args.iter().map(slot).collect::<Vec<_>>();
+        let operation = match op {
+            Op::Unary(UnaryOperator::Not) => format!("not {}", args[0]),
+            Op::Unary(_) => format!("{}{}", op.name(), args[0]),
+            Op::Binary(_) => format!("{} {} {}", args[0], op.name(), args[1]),
+            Op::Powi(n) => format!("{} ^ {n}", args[0]),
+            Op::Function(function) => format!("{function}({})", args.join(", ")),
+            Op::Window(..) => unreachable!("windows are computed between the stages of a pipeline"),
+        };
+        write!(out, "  {} = {operation}", slot(dst)).unwrap();
+        match guard {
+            Some(guard) => {
+                let condition = |&(value, truthy): &(Slot<T>, bool)| {
+                    format!("{} is {}", slot(&value), if truthy { "not 0" } else { "0" })
+                };
+                let conjunction =
+                    |all: &Vec<(Slot<T>, bool)>| all.iter().map(condition).collect::<Vec<_>>().join(" and ");
+                let guard = guard.iter().map(conjunction).collect::<Vec<_>>();
+                writeln!(out, ", checked where {}", guard.join(" or ")).unwrap()
+            }
+            None => writeln!(out).unwrap(),
+        }
+    }
+
+    // Results that are copied to their output at the end
+    for (k, result) in plan.results.iter().enumerate() {
+        if *result != Slot::Output(k) {
+            writeln!(out, "  out{k} = {}", slot(result)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::plan::Pipeline;
+    use crate::engine::vectorized;
+    use crate::Expression;
+
+    #[test]
+    fn explain() {
+        let expression = Expression::parse("(a - b) * (a - b) + -c").unwrap();
+        // Depends on how large a chunk is tuned to be
+        let chunk_rows = vectorized::chunk_rows::<f64, 4>(&Pipeline::new(&expression).last, 3);
+        let engine = expression.to_vectorized_engine();
+        let chunks = format!("fused chunks: {chunk_rows} rows");
+        let expected = [
+            "postfix: a b - a b - * c neg +",
+            "stack depth: 3",
+            "lanes: 4 x f64, 256 bit vectors",
+            &chunks,
+            "plan: 1 scratch column",
+            "  out0 = a - b",
+            "  out0 = out0 * out0",
+            "  scratch0 = -c",
+            "  out0 = out0 + scratch0",
+        ];
+        assert_eq!(engine.explain().lines().collect::<Vec<_>>(), expected);
+
+        let engine = Expression::parse("a")
+            .unwrap()
+            .to_typed_vectorized_engine::<f32, 16>()
+            .unwrap();
+        assert!(engine.explain().contains("lanes: 16 x f32, 512 bit vectors"));
+        assert!(engine.explain().ends_with("plan: 0 scratch columns\n  out0 = a\n"));
+    }
+
+    #[test]
+    fn explain_windows() {
+        let engine = Expression::parse("a - lag(a * 2, 1)").unwrap().to_vectorized_engine();
+        let explain = engine.explain();
+        let stage = explain
+            .lines()
+            .skip_while(|line| !line.starts_with("stage 1"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stage[..5],
+            [
+                "stage 1: 0 scratch columns",
+                "  out0 = a * 2",
+                "  out1 = 1",
+                "  window0 = lag(out0, 1)",
+                "  window1 = lag(out1, 1)",
+            ]
+        );
+        // Null rows at the start don't fail the float policy
+        assert!(explain.contains("out0 = a - window0, checked where window1 is not 0"));
+    }
+
+    #[test]
+    fn timings() {
+        let engine = Expression::parse("(a - b) * (a - b) - -c")
+            .unwrap()
+            .to_vectorized_engine();
+        let a = vec![1.0; 1000];
+        let mut output = vec![0.0; a.len()];
+        engine.evaluate(&[&a, &a, &a], &mut output).unwrap();
+        assert!(engine.timings().is_empty());
+
+        let engine = engine.with_timings();
+        engine.evaluate(&[&a, &a, &a], &mut output).unwrap();
+        engine.evaluate_fused(&[&a, &a, &a], &mut output).unwrap();
+        let timings = engine.timings();
+        assert_eq!(timings.keys().copied().collect::<Vec<_>>(), ["*", "-", "neg"]);
+        // Subtraction runs twice for every run over the rows, and the fused evaluation has two chunks
+        assert_eq!((timings["-"].calls, timings["-"].rows), (6, 4000));
+        assert_eq!((timings["neg"].calls, timings["neg"].rows), (3, 2000));
+
+        engine.reset_timings();
+        assert!(engine.timings().is_empty());
+    }
+}
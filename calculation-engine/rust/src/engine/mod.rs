@@ -1,12 +1,42 @@
+pub mod compiled;
+pub(crate) mod dag;
+pub mod element;
+pub mod explain;
+pub(crate) mod plan;
+pub mod pool;
+pub mod reduction;
 pub mod scalar;
 pub mod vectorized;
+pub(crate) mod window;
 
 use thiserror::Error;
 
+use crate::Function;
+
 #[derive(Error, Debug)]
 pub enum EvaluationError {
     #[error("input length {0} does not match required input length {1}")]
     InvalidInputLength(usize, usize),
     #[error("input length {0} does not match required input length {1} at column {2}")]
     InvalidInputColumnLength(usize, usize, usize),
+    #[error("output length {0} does not match input column length {1}")]
+    InvalidOutputLength(usize, usize),
+    #[error("validity length {0} is shorter than the required {1} bytes at column {2}")]
+    InvalidValidityLength(usize, usize, usize),
+    #[error("validity count {0} does not match input column count {1}")]
+    InvalidValidityCount(usize, usize),
+    #[error("output validity length {0} is shorter than the required {1} bytes")]
+    InvalidOutputValidityLength(usize, usize),
+    #[error("key length {0} does not match input column length {1}")]
+    InvalidKeyLength(usize, usize),
+    #[error("output count {0} does not match expression count {1}")]
+    InvalidOutputCount(usize, usize),
+    #[error("window function {0} needs more than a single row")]
+    UnsupportedWindow(Function),
+    #[error("no input was provided for variable '{0}'")]
+    MissingInput(String),
+    #[error("{operation} overflowed at row {row}")]
+    Overflow { row: usize, operation: &'static str },
+    #[error("{operation} resulted in NaN or infinity at row {row}")]
+    NonFinite { row: usize, operation: &'static str },
 }
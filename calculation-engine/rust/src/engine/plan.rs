@@ -0,0 +1,389 @@
+use crate::{Expression, Function, Node, Operator, UnaryOperator};
+
+use super::dag::{Dag, Value};
+use super::element::Element;
+use super::vectorized::{self, Checks, Column};
+
+/// Where a step reads an argument from or writes its result to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Slot<T = f64> {
+    Input(usize),
+    Literal(T),
+    Scratch(usize),
+    Output(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Op {
+    Unary(UnaryOperator),
+    Binary(Operator),
+    /// `x ^ n` for a constant `n` that multiplication or division computes exactly like `powf`, see `integer_exponent`
+    Powi(i32),
+    Function(Function),
+    /// Window function of a constant size, computed over the whole column of its argument, see `Pipeline`
+    Window(Function, usize),
+}
+
+impl Op {
+    /// Operator or function name, for error messages
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Op::Unary(UnaryOperator::Plus) => "+",
+            Op::Unary(UnaryOperator::Neg) => "-",
+            Op::Unary(UnaryOperator::Not) => "not",
+            Op::Binary(operator) => operator.symbol(),
+            Op::Powi(_) => Operator::Pow.symbol(),
+            Op::Function(function) | Op::Window(function, _) => function.name(),
+        }
+    }
+
+    /// Whether the operation can overflow, or turn finite arguments into NaN or infinity, see `Checks`
+    pub(crate) fn can_fail(self) -> bool {
+        matches!(
+            self,
+            Op::Unary(UnaryOperator::Neg)
+                | Op::Binary(Operator::Add | Operator::Sub | Operator::Mul | Operator::Div | Operator::Pow)
+                | Op::Powi(_)
+                | Op::Function(Function::Abs | Function::Sqrt | Function::Exp | Function::Ln)
+        )
+    }
+
+    /// The operation a postfix node performs, `None` for variables and literals
+    pub(crate) fn from_node(node: &Node) -> Option<Op> {
+        match node {
+            Node::Unary(operator) => Some(Op::Unary(*operator)),
+            Node::Operator(operator) => Some(Op::Binary(*operator)),
+            Node::Function(function) => Some(Op::Function(*function)),
+            _ => None,
+        }
+    }
+}
+
+/// Rows where the result of an operation is used, any of the conjunctions of values that are either truthy or 0.
+/// Failed checks in other rows are ignored, e.g. in null rows or in the branch of an `if` that isn't taken.
+pub(crate) type Guard<V> = Vec<Vec<(V, bool)>>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Step<T = f64> {
+    pub(crate) op: Op,
+    pub(crate) args: Vec<Slot<T>>,
+    pub(crate) dst: Slot<T>,
+    /// Every row is checked without one
+    pub(crate) guard: Option<Guard<Slot<T>>>,
+}
+
+/// Column-at-a-time evaluation order for the vectorized engine.
+/// Scratch columns are reused once their value has been consumed, repeated subexpressions are computed once.
+#[derive(Clone, Debug)]
+pub(crate) struct Plan<T = f64> {
+    pub(crate) steps: Vec<Step<T>>,
+    pub(crate) scratch_columns: usize,
+    /// Where the value of each output ends up. When it isn't `Slot::Output` of the same index,
+    /// e.g. for `a` or `1 + 2`, it's copied there at the end
+    pub(crate) results: Vec<Slot<T>>,
+}
+
+/// Plan for the arguments of the windows that are computed after it, one output per window and its size
+pub(crate) type Stage<T> = (Plan<T>, Vec<(Function, usize)>);
+
+/// Plans that run one after the other over all rows, each computing the arguments of the windows after it.
+#[derive(Clone, Debug)]
+pub(crate) struct Pipeline<T = f64> {
+    pub(crate) stages: Vec<Stage<T>>,
+    pub(crate) last: Plan<T>,
+}
+
+impl<T: Element> Pipeline<T> {
+    pub(crate) fn new(expression: &Expression) -> Self {
+        let mut dag = Dag::default();
+        let root = dag.add(expression);
+        Self::from_dag(dag, &[root], expression.required_input_length())
+    }
+
+    /// One pipeline for all of `expressions`, where output `k` gets the value of `expressions[k]`.
+    /// The expressions read the same input columns, see `ExpressionSet`.
+    pub(crate) fn from_expressions(expressions: &[Expression], inputs: usize) -> Self {
+        let mut dag = Dag::default();
+        let roots = expressions
+            .iter()
+            .map(|expression| dag.add(expression))
+            .collect::<Vec<_>>();
+        Self::from_dag(dag, &roots, inputs)
+    }
+
+    /// Pipeline with a second output that holds the validity of the first, see `Dag::add_nullable`.
+    /// `inputs` counts the validity columns as well.
+    pub(crate) fn nullable(expression: &Expression, validity: &[Option<usize>], inputs: usize) -> Self {
+        let mut dag = Dag::default();
+        let (value, validity) = dag.add_nullable(expression, validity);
+        Self::from_dag(dag, &[value, validity], inputs)
+    }
+
+    /// Windows of windows, like `lag(rolling_mean(x, 3))`, need a stage for each level of nesting
+    fn from_dag(mut dag: Dag<T>, roots: &[usize], inputs: usize) -> Self {
+        dag.guard_branches(roots);
+
+        // Number of windows nested in each value
+        let mut levels = vec![0; dag.values.len()];
+        for (id, value) in dag.values.iter().enumerate() {
+            if let Value::Apply(op, args) = value {
+                let level = args.iter().chain(&dag.guard_values(id)).map(|&arg| levels[arg]).max();
+                levels[id] = level.unwrap_or(0) + matches!(op, Op::Window(..)) as usize;
+            }
+        }
+
+        // Windows in the order of their columns, by level
+        let mut windows = dag
+            .values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| matches!(value, Value::Apply(Op::Window(..), _)))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        windows.sort_by_key(|&id| levels[id]);
+        let column = |id: usize, level: usize| {
+            let k = windows.iter().position(|&window| window == id)?;
+            (levels[id] <= level).then_some(inputs + k)
+        };
+
+        let depth = windows.last().map_or(0, |&id| levels[id]);
+        let stages = (1..=depth)
+            .map(|level| {
+                let (ops, args): (Vec<_>, Vec<_>) = windows
+                    .iter()
+                    .filter(|&&id| levels[id] == level)
+                    .map(|&id| match &dag.values[id] {
+                        Value::Apply(Op::Window(function, size), args) => ((*function, *size), args[0]),
+                        _ => unreachable!(),
+                    })
+                    .unzip();
+                let (stage, args) = dag.extract(&args, |id| column(id, level - 1));
+                (Plan::from_dag(&stage, &args), ops)
+            })
+            .collect();
+        let (last, roots) = dag.extract(roots, |id| column(id, depth));
+
+        Pipeline {
+            stages,
+            last: Plan::from_dag(&last, &roots),
+        }
+    }
+}
+
+impl<T: Element> Plan<T> {
+    /// Computes every operation in the DAG once, each value keeps its column until its last use.
+    pub(crate) fn from_dag(dag: &Dag<T>, roots: &[usize]) -> Self {
+        let mut last_use: Vec<Option<usize>> = vec![None; dag.values.len()];
+        for (id, value) in dag.values.iter().enumerate() {
+            if let Value::Apply(_, args) = value {
+                for &arg in args.iter().chain(&dag.guard_values(id)) {
+                    last_use[arg] = Some(id);
+                }
+            }
+        }
+        for &root in roots {
+            last_use[root] = None;
+        }
+
+        let mut steps = Vec::new();
+        let mut registers = Registers::new(roots.len());
+        let mut slots: Vec<Slot<T>> = Vec::with_capacity(dag.values.len());
+
+        for (id, value) in dag.values.iter().enumerate() {
+            let (op, args) = match value {
+                Value::Input(i) => {
+                    slots.push(Slot::Input(*i));
+                    continue;
+                }
+                Value::Literal(bits) => {
+                    slots.push(Slot::Literal(T::from_bits(*bits)));
+                    continue;
+                }
+                Value::Apply(op, args) => (*op, args),
+            };
+
+            // Every operation is lane-wise, so the result can go straight into a column that was just read.
+            // The same value may be more than one of the arguments, e.g. for `(a - b) * (a - b)`
+            let reads = args.iter().chain(&dag.guard_values(id)).copied().collect::<Vec<_>>();
+            for (k, &arg) in reads.iter().enumerate() {
+                if last_use[arg] == Some(id) && !reads[..k].contains(&arg) {
+                    registers.release(slots[arg]);
+                }
+            }
+            let dst = registers.allocate(roots.iter().position(|&root| root == id));
+
+            let args = args.iter().map(|&arg| slots[arg]).collect();
+            let guard = dag.guards.get(&id).map(|guard| {
+                let conjunction =
+                    |all: &Vec<(usize, bool)>| all.iter().map(|&(id, truthy)| (slots[id], truthy)).collect();
+                guard.iter().map(conjunction).collect()
+            });
+            steps.push(Step { op, args, dst, guard });
+            slots.push(dst);
+        }
+
+        Plan {
+            steps,
+            scratch_columns: registers.scratch_columns,
+            results: roots.iter().map(|&root| slots[root]).collect(),
+        }
+    }
+}
+
+/// Tracks which columns are free to hold an intermediate result
+struct Registers {
+    outputs_taken: Vec<bool>,
+    free: Vec<usize>,
+    scratch_columns: usize,
+}
+
+impl Registers {
+    fn new(outputs: usize) -> Self {
+        Self {
+            outputs_taken: vec![false; outputs],
+            free: Vec::new(),
+            scratch_columns: 0,
+        }
+    }
+
+    /// Prefers the column of `output`, where the result has to end up anyway, and scratch when it's taken.
+    fn allocate<T>(&mut self, output: Option<usize>) -> Slot<T> {
+        let free_output = match output {
+            Some(k) => (!self.outputs_taken[k]).then_some(k),
+            None => self.outputs_taken.iter().position(|&taken| !taken),
+        };
+        if let Some(k) = free_output {
+            self.outputs_taken[k] = true;
+            return Slot::Output(k);
+        }
+
+        match self.free.pop() {
+            Some(i) => Slot::Scratch(i),
+            None => {
+                self.scratch_columns += 1;
+                Slot::Scratch(self.scratch_columns - 1)
+            }
+        }
+    }
+
+    fn release<T>(&mut self, slot: Slot<T>) {
+        match slot {
+            Slot::Output(k) => self.outputs_taken[k] = false,
+            Slot::Scratch(i) => self.free.push(i),
+            Slot::Input(_) | Slot::Literal(_) => {}
+        }
+    }
+}
+
+/// Computes an operation on literals the same way the engine would, `None` when it overflows
+/// or turns finite literals into NaN or infinity, so that the error is left to evaluation
+pub(crate) fn fold<T: Element>(op: Op, args: &[T]) -> Option<T> {
+    let args = args.iter().map(|&value| Column::Splat(value)).collect::<Vec<_>>();
+
+    let mut value = T::default();
+    let checks = Checks {
+        overflow: true,
+        non_finite: true,
+        replacement: None,
+    };
+    vectorized::run::<T, 1>(op, &args, &mut value, 1, checks, None).ok()?;
+    Some(value)
+}
+
+/// Exponents where `x * x` or `1 / x` is cheaper than `powf` and rounds the same.
+/// Larger powers round differently through repeated multiplication, and can overflow before a reciprocal.
+pub(crate) fn integer_exponent(exponent: f64) -> Option<i32> {
+    (-1.0..=2.0)
+        .contains(&exponent)
+        .then_some(exponent as i32)
+        .filter(|&n| n as f64 == exponent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExpressionSet;
+
+    fn build(source: &str) -> Plan {
+        Pipeline::new(&Expression::parse(source).unwrap()).last
+    }
+
+    #[test]
+    fn chains_need_no_scratch() {
+        let plan = build("sqrt(a + b * c) / d - e");
+        assert_eq!(plan.scratch_columns, 0);
+        assert_eq!(plan.results, [Slot::Output(0)]);
+    }
+
+    #[test]
+    fn scratch_columns_are_reused() {
+        // Each product is live at the same time as the running sum, but never two products at once
+        let plan = build("a * b + c * d + e * f + g * h");
+        assert_eq!(plan.steps.len(), 7);
+        assert_eq!(plan.scratch_columns, 1);
+
+        let plan = build("(a + b) * (c + d) - (e + f) * (g + h)");
+        assert_eq!(plan.scratch_columns, 2);
+    }
+
+    #[test]
+    fn literals_are_folded() {
+        let plan = build("a * (2 ^ 3 - 1) + -(4 / 2)");
+        assert_eq!(plan.steps.len(), 2);
+        assert_eq!(plan.steps[0].args, [Slot::Input(0), Slot::Literal(7.0)]);
+        assert_eq!(plan.steps[1].args, [Slot::Output(0), Slot::Literal(-2.0)]);
+
+        let plan = build("max(1, 2) * 3");
+        assert!(plan.steps.is_empty());
+        assert_eq!(plan.results, [Slot::Literal(6.0)]);
+    }
+
+    #[test]
+    fn shared_intermediates() {
+        let plan = build("(a - b) * (a - b) + sqrt(a - b)");
+        assert_eq!(plan.steps.len(), 4);
+        // `a - b` is live until the square root, while the product is held in a scratch column
+        assert_eq!(plan.steps[0].dst, Slot::Output(0));
+        assert_eq!(plan.steps[1].args, [Slot::Output(0), Slot::Output(0)]);
+        assert_eq!(plan.steps[1].dst, Slot::Scratch(0));
+        assert_eq!(plan.steps[2].args, [Slot::Output(0)]);
+        assert_eq!(plan.steps[3].args, [Slot::Scratch(0), Slot::Output(0)]);
+        assert_eq!(plan.steps[3].dst, Slot::Output(0));
+        assert_eq!(plan.scratch_columns, 1);
+    }
+
+    #[test]
+    fn small_integer_powers() {
+        let plan = build("x ^ 2 + x ^ -1 + x ^ 3 + x ^ 0.5");
+        assert_eq!(plan.steps[0].op, Op::Powi(2));
+        assert_eq!(plan.steps[1].op, Op::Powi(-1));
+        assert_eq!(plan.steps[3].op, Op::Binary(Operator::Pow));
+        assert_eq!(plan.steps[5].op, Op::Binary(Operator::Pow));
+    }
+
+    #[test]
+    fn expressions_share_intermediates() {
+        let set = ExpressionSet::new(["a + b", "(a + b) * c", "a"].map(|source| Expression::parse(source).unwrap()));
+        let plan = Pipeline::<f64>::from_expressions(set.expressions(), set.variables().len()).last;
+        assert_eq!(plan.steps.len(), 2);
+        assert_eq!(plan.steps[1].args, [Slot::Output(0), Slot::Input(2)]);
+        assert_eq!(plan.results, [Slot::Output(0), Slot::Output(1), Slot::Input(0)]);
+    }
+
+    #[test]
+    fn windows_are_computed_between_stages() {
+        let pipeline = Pipeline::<f64>::new(&Expression::parse("lag(rolling_sum(x + 1, 3)) - lag(x)").unwrap());
+        assert_eq!(pipeline.stages.len(), 2);
+        // Validity is a window as well, over 1 since `x` is never null
+        let (plan, windows) = &pipeline.stages[0];
+        assert_eq!(windows.len(), 4);
+        assert_eq!(plan.steps[0].op, Op::Binary(Operator::Add));
+        let (_, windows) = &pipeline.stages[1];
+        assert_eq!(windows, &[(Function::Lag, 1), (Function::Lag, 1)]);
+        assert!(pipeline
+            .last
+            .steps
+            .iter()
+            .all(|step| step.args.iter().all(|arg| arg != &Slot::Input(0))));
+    }
+}
@@ -0,0 +1,123 @@
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Worker threads that `vectorized::Engine::evaluate_parallel` splits rows over, kept until the pool is dropped.
+#[derive(Debug)]
+pub struct ThreadPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads: NonZeroUsize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.get())
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || work(&receiver))
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs every task on the workers and waits for all of them, returning their results in order.
+    /// Panics after every task has finished when any of them panicked.
+    pub(crate) fn run<'a, R: Send + 'a>(&self, tasks: Vec<Box<dyn FnOnce() -> R + Send + 'a>>) -> Vec<R> {
+        let count = tasks.len();
+        let (results, receiver) = mpsc::channel();
+        for (k, task) in tasks.into_iter().enumerate() {
+            let results = results.clone();
+            let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+                let _ = results.send((k, task()));
+            });
+            // The borrows of a task outlive it, since every job has been run or dropped
+            // once all senders are gone, which is what the loop below waits for
+            let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'a>, Job>(job) };
+            self.jobs
+                .as_ref()
+                .unwrap()
+                .send(job)
+                .expect("workers run until the pool is dropped");
+        }
+        drop(results);
+
+        let mut ordered = (0..count).map(|_| None).collect::<Vec<_>>();
+        for (k, result) in receiver {
+            ordered[k] = Some(result);
+        }
+        ordered
+            .into_iter()
+            .map(|result| result.expect("a task of the thread pool panicked"))
+            .collect()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Workers stop once the channel is closed and empty
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            // A panicking task is reported by `run`, the worker keeps going
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tasks_borrow_and_return_in_order() {
+        let pool = ThreadPool::new(NonZeroUsize::new(3).unwrap());
+        let mut values = [1, 2, 3, 4, 5];
+        for _ in 0..2 {
+            let tasks = values
+                .chunks_mut(2)
+                .map(|chunk| {
+                    Box::new(move || {
+                        chunk.iter_mut().for_each(|value| *value *= 10);
+                        chunk.len()
+                    }) as Box<dyn FnOnce() -> usize + Send>
+                })
+                .collect();
+            assert_eq!(pool.run(tasks), [2, 2, 1]);
+        }
+        assert_eq!(values, [100, 200, 300, 400, 500]);
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        let pool = ThreadPool::new(NonZeroUsize::MIN);
+        let tasks: Vec<Box<dyn FnOnce() -> i32 + Send>> = vec![Box::new(|| 1), Box::new(|| panic!("task"))];
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| pool.run(tasks))).is_err());
+        // The worker is still there
+        assert_eq!(pool.run(vec![Box::new(|| 2)]), [2]);
+    }
+}
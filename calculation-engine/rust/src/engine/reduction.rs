@@ -0,0 +1,281 @@
+use std::simd::num::SimdFloat;
+use std::simd::Simd;
+
+use super::element::Element;
+
+/// Reduces the values of an expression over all rows to a single value, accumulated in `f64`,
+/// except for sums of integers, which are exact
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Reduction {
+    /// Overflows when the sum of integers doesn't fit in `i64`, see `Overflow`
+    Sum,
+    Mean,
+    /// NaN rows are skipped, the minimum is NaN when every row is
+    Min,
+    /// NaN rows are skipped, the maximum is NaN when every row is
+    Max,
+    /// Number of rows
+    Count,
+    /// Sample variance, with `n - 1` degrees of freedom
+    Variance,
+}
+
+/// Partial result of a reduction, where each of the `N` lanes accumulates its own rows
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Lanes<const N: usize> {
+    reduction: Reduction,
+    /// Rows that every lane has seen
+    count: usize,
+    /// Sum, minimum or maximum, or the mean for `Reduction::Variance`
+    value: Simd<f64, N>,
+    /// Sum of squared differences from the mean, for `Reduction::Variance`
+    m2: Simd<f64, N>,
+    /// Sum of integers, for `Reduction::Sum` and `Reduction::Mean`
+    integers: Option<[i128; N]>,
+}
+
+impl<const N: usize> Lanes<N> {
+    pub(crate) fn new(reduction: Reduction) -> Self {
+        // The minimum and maximum of NaN and any other value is the other value
+        let value = match reduction {
+            Reduction::Min | Reduction::Max => f64::NAN,
+            _ => 0.0,
+        };
+        Self {
+            reduction,
+            count: 0,
+            value: Simd::splat(value),
+            m2: Simd::splat(0.0),
+            integers: None,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn push<T: Element>(&mut self, x: Simd<T, N>) {
+        self.count += 1;
+        if let (Reduction::Sum | Reduction::Mean, Some(x)) = (self.reduction, T::integers(x)) {
+            let integers = self.integers.get_or_insert([0; N]);
+            for (sum, x) in integers.iter_mut().zip(x.to_array()) {
+                *sum += x as i128;
+            }
+            return;
+        }
+
+        let x = T::widen(x);
+        match self.reduction {
+            Reduction::Sum | Reduction::Mean => self.value += x,
+            Reduction::Min => self.value = self.value.simd_min(x),
+            Reduction::Max => self.value = self.value.simd_max(x),
+            Reduction::Count => {}
+            // Welford's algorithm, which doesn't lose precision to large squares like the sum of squares does
+            Reduction::Variance => {
+                let delta = x - self.value;
+                self.value += delta / Simd::splat(self.count as f64);
+                self.m2 += delta * (x - self.value);
+            }
+        }
+    }
+
+    /// Combines the lanes into one partial result
+    pub(crate) fn partial(&self) -> Partial {
+        let lanes = (0..N).map(|k| Partial {
+            count: self.count,
+            value: self.value[k],
+            m2: self.m2[k],
+            integer: self.integers.map(|integers| integers[k]),
+        });
+        lanes.fold(Partial::EMPTY, |a, b| a.merge(b, self.reduction))
+    }
+}
+
+/// Reduction of any number of rows, which can be combined with the reduction of other rows
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Partial {
+    count: usize,
+    value: f64,
+    m2: f64,
+    integer: Option<i128>,
+}
+
+impl Partial {
+    const EMPTY: Partial = Partial {
+        count: 0,
+        value: 0.0,
+        m2: 0.0,
+        integer: None,
+    };
+
+    pub(crate) fn merge(self, other: Partial, reduction: Reduction) -> Partial {
+        if self.count == 0 {
+            return other;
+        }
+        if other.count == 0 {
+            return self;
+        }
+
+        let count = self.count + other.count;
+        let (value, m2) = match reduction {
+            Reduction::Sum | Reduction::Mean | Reduction::Count => (self.value + other.value, 0.0),
+            Reduction::Min => (self.value.min(other.value), 0.0),
+            Reduction::Max => (self.value.max(other.value), 0.0),
+            // Chan et al., the pairwise form of Welford's algorithm
+            Reduction::Variance => {
+                let delta = other.value - self.value;
+                let weight = other.count as f64 / count as f64;
+                let value = self.value + delta * weight;
+                let m2 = self.m2 + other.m2 + delta * delta * self.count as f64 * weight;
+                (value, m2)
+            }
+        };
+        let integer = self.integer.zip(other.integer).map(|(a, b)| a + b);
+        Partial {
+            count,
+            value,
+            m2,
+            integer,
+        }
+    }
+
+    /// Whether the sum of integers doesn't fit in `i64`, `finish` wraps it around
+    pub(crate) fn overflows(&self, reduction: Reduction) -> bool {
+        reduction == Reduction::Sum && self.integer.is_some_and(|sum| i64::try_from(sum).is_err())
+    }
+
+    /// `None` when there are no rows, or only one for the variance
+    pub(crate) fn finish(self, reduction: Reduction) -> Option<f64> {
+        match reduction {
+            Reduction::Count => Some(self.count as f64),
+            _ if self.count == 0 => None,
+            Reduction::Sum => Some(self.integer.map_or(self.value, |sum| sum as i64 as f64)),
+            Reduction::Min | Reduction::Max => Some(self.value),
+            Reduction::Mean => Some(self.integer.map_or(self.value, |sum| sum as f64) / self.count as f64),
+            Reduction::Variance if self.count == 1 => None,
+            Reduction::Variance => Some(self.m2 / (self.count - 1) as f64),
+        }
+    }
+}
+
+/// Reduces the rows of a column `N` at a time, with any rows that don't fill a vector reduced on their own
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Reducer<const N: usize> {
+    reduction: Reduction,
+    lanes: Lanes<N>,
+    remainder: Lanes<1>,
+}
+
+impl<const N: usize> Reducer<N> {
+    pub(crate) fn new(reduction: Reduction) -> Self {
+        Self {
+            reduction,
+            lanes: Lanes::new(reduction),
+            remainder: Lanes::new(reduction),
+        }
+    }
+
+    /// Rows where `validity` is 0 are null and skipped
+    pub(crate) fn update<T: Element>(&mut self, values: &[T], validity: Option<&[T]>) {
+        let Some(validity) = validity else {
+            let mut vectors = values.chunks_exact(N);
+            for vector in &mut vectors {
+                self.lanes.push(Simd::<T, N>::from_slice(vector));
+            }
+            for &value in vectors.remainder() {
+                self.remainder.push(Simd::splat(value));
+            }
+            return;
+        };
+
+        // Null rows are rare, so vectors without any keep the lanes busy and the rest go one row at a time
+        let mut vectors = values.chunks_exact(N).zip(validity.chunks_exact(N));
+        for (vector, valid) in &mut vectors {
+            if valid.iter().all(|&valid| valid != T::default()) {
+                self.lanes.push(Simd::<T, N>::from_slice(vector));
+            } else {
+                self.update_rows(vector, valid);
+            }
+        }
+        let rows = values.len() / N * N;
+        self.update_rows(&values[rows..], &validity[rows..]);
+    }
+
+    fn update_rows<T: Element>(&mut self, values: &[T], validity: &[T]) {
+        for (&value, &valid) in values.iter().zip(validity) {
+            if valid != T::default() {
+                self.remainder.push(Simd::splat(value));
+            }
+        }
+    }
+
+    pub(crate) fn partial(&self) -> Partial {
+        self.lanes.partial().merge(self.remainder.partial(), self.reduction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reduce(reduction: Reduction, values: &[f64]) -> Option<f64> {
+        let mut reducer = Reducer::<4>::new(reduction);
+        // Split up like chunks of rows would be, so that there is a remainder in between
+        let (first, second) = values.split_at(values.len() / 2);
+        reducer.update(first, None);
+        reducer.update(second, None);
+        reducer.partial().finish(reduction)
+    }
+
+    #[test]
+    fn reductions() {
+        let values = (1..=11).map(f64::from).collect::<Vec<_>>();
+        assert_eq!(reduce(Reduction::Sum, &values), Some(66.0));
+        assert_eq!(reduce(Reduction::Mean, &values), Some(6.0));
+        assert_eq!(reduce(Reduction::Min, &values), Some(1.0));
+        assert_eq!(reduce(Reduction::Max, &values), Some(11.0));
+        assert_eq!(reduce(Reduction::Count, &values), Some(11.0));
+        assert_eq!(reduce(Reduction::Variance, &values), Some(11.0));
+    }
+
+    #[test]
+    fn nan_rows_are_skipped() {
+        let mut values = vec![f64::NAN; 11];
+        assert!(reduce(Reduction::Min, &values).unwrap().is_nan());
+        assert!(reduce(Reduction::Max, &values).unwrap().is_nan());
+        values[9] = -1.0;
+        assert_eq!(reduce(Reduction::Min, &values), Some(-1.0));
+        assert_eq!(reduce(Reduction::Max, &values), Some(-1.0));
+    }
+
+    #[test]
+    fn integer_sums_are_exact() {
+        let reduce = |reduction, values: &[i64]| {
+            let mut reducer = Reducer::<4>::new(reduction);
+            reducer.update(values, None);
+            reducer.partial()
+        };
+        // Each lane holds one of the values, which would round to 2^63 in `f64`
+        let values = [i64::MAX, 1, -i64::MAX, 0, 1];
+        assert_eq!(reduce(Reduction::Sum, &values).finish(Reduction::Sum), Some(2.0));
+        assert_eq!(reduce(Reduction::Mean, &values).finish(Reduction::Mean), Some(0.4));
+
+        let sum = reduce(Reduction::Sum, &[i64::MAX, 1]);
+        assert!(sum.overflows(Reduction::Sum));
+        assert_eq!(sum.finish(Reduction::Sum), Some(i64::MIN as f64));
+    }
+
+    #[test]
+    fn empty_reductions() {
+        assert_eq!(reduce(Reduction::Sum, &[]), None);
+        assert_eq!(reduce(Reduction::Count, &[]), Some(0.0));
+        assert_eq!(reduce(Reduction::Variance, &[1.0]), None);
+        assert_eq!(reduce(Reduction::Variance, &[1.0, 3.0]), Some(2.0));
+    }
+
+    #[test]
+    fn variance_is_stable() {
+        // The sum of squares would lose every significant digit at this offset, the mean only some of its last ones
+        let values = (0..1000).map(|k| 1e9 + (k % 2) as f64).collect::<Vec<_>>();
+        let variance = reduce(Reduction::Variance, &values).unwrap();
+        let expected = 0.25 * 1000.0 / 999.0;
+        assert!((variance - expected).abs() < expected * 1e-6);
+    }
+}
@@ -1,40 +1,127 @@
 use std::result::Result;
-use tinyvec::tiny_vec;
+use tinyvec::TinyVec;
 
+use crate::engine::element::FloatPolicy;
+use crate::engine::plan::Op;
 use crate::engine::EvaluationError;
 use crate::Expression;
+use crate::Function;
 use crate::Node;
 use crate::Operator;
+use crate::UnaryOperator;
 
 pub struct Engine {
     expression: Expression,
+    /// Short-circuiting for `if`, `and` and `or`, applied after evaluating the node at the same index
+    controls: Vec<Option<Control>>,
+    /// Number of variable nodes before each node, to find the next operand after a jump
+    operands_before: Vec<usize>,
+    /// The first window function, which can't be evaluated one row at a time
+    window: Option<Function>,
+    float: FloatPolicy,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Control {
+    /// End of an `if` condition, pops the condition and jumps to `otherwise` when it is false
+    Branch { otherwise: usize },
+    /// End of the `then` branch of an `if`, skips the `otherwise` branch
+    Jump { to: usize },
+    /// End of the left side of `and`/`or`, pops it and jumps to `to` with the result if it equals `when`
+    ShortCircuit { when: bool, to: usize },
 }
 
 impl Engine {
     pub(crate) fn new(expression: Expression) -> Self {
-        Self { expression }
+        let expression = without_nulls(expression);
+        let nodes = &expression.nodes;
+        let mut controls = vec![None; nodes.len()];
+
+        // (first, last) node index of each subtree on the stack
+        let mut subtrees: Vec<(usize, usize)> = Vec::with_capacity(nodes.len());
+        for (k, node) in nodes.iter().enumerate() {
+            let children = subtrees.split_off(subtrees.len() - node.arity());
+            match node {
+                Node::Function(Function::If) => {
+                    controls[children[0].1] = Some(Control::Branch {
+                        otherwise: children[2].0,
+                    });
+                    controls[children[1].1] = Some(Control::Jump { to: k });
+                }
+                Node::Operator(Operator::And) => {
+                    controls[children[0].1] = Some(Control::ShortCircuit { when: false, to: k })
+                }
+                Node::Operator(Operator::Or) => {
+                    controls[children[0].1] = Some(Control::ShortCircuit { when: true, to: k })
+                }
+                _ => {}
+            }
+            subtrees.push((children.first().map_or(k, |c| c.0), k));
+        }
+
+        let operands_before = nodes
+            .iter()
+            .scan(0, |count, node| {
+                let before = *count;
+                *count += matches!(node, Node::Variable(_)) as usize;
+                Some(before)
+            })
+            .collect();
+
+        let window = nodes.iter().find_map(|node| match node {
+            Node::Function(function) if function.is_window() => Some(*function),
+            _ => None,
+        });
+
+        Self {
+            expression,
+            controls,
+            operands_before,
+            window,
+            float: FloatPolicy::default(),
+        }
     }
 
+    /// What happens when an operation results in NaN or infinity, propagated by default.
+    /// Errors are reported at row 0, since there is only the one row.
+    pub fn with_float_policy(mut self, float: FloatPolicy) -> Self {
+        self.float = float;
+        self
+    }
+
+    /// Evaluates a single row, `input` is ordered like `Expression::variables`
     pub fn evaluate(&self, input: &[f64]) -> Result<f64, EvaluationError> {
-        if input.len() != self.expression.required_input_length {
+        if input.len() != self.expression.required_input_length() {
             return Err(EvaluationError::InvalidInputLength(
                 input.len(),
-                self.expression.required_input_length,
+                self.expression.required_input_length(),
             ));
         }
+        if let Some(function) = self.window {
+            return Err(EvaluationError::UnsupportedWindow(function));
+        }
 
-        const MAX_STACK_SIZE: usize = 16;
-        let mut stack = tiny_vec!([f64; MAX_STACK_SIZE]);
+        // Construction has checked that every pop below has a value to take,
+        // shallow expressions are kept inline and only deep ones allocate
+        const INLINE_STACK_SIZE: usize = 16;
+        let mut stack = TinyVec::<[f64; INLINE_STACK_SIZE]>::with_capacity(self.expression.max_stack_depth);
 
         let expr = &self.expression.nodes[..];
         let mut operand_index = 0;
-        for op in expr {
-            match op {
-                Node::Operand => {
-                    stack.push(input[operand_index]);
+        let mut k = 0;
+        while k < expr.len() {
+            match &expr[k] {
+                Node::Variable(_) => {
+                    stack.push(input[self.expression.operands[operand_index]]);
                     operand_index += 1;
                 }
-                Node::Operator(_) => {
+                Node::Literal(value) => stack.push(*value),
+                // Only the right side is left on the stack, see `Control::ShortCircuit`
+                Node::Operator(Operator::And | Operator::Or) => {
+                    let right = stack.pop().unwrap();
+                    stack.push(from_bool(truthy(right)));
+                }
+                op @ Node::Operator(_) => {
                     let right = stack.pop().unwrap();
                     let left = stack.pop().unwrap();
                     let result = match op {
@@ -42,15 +129,150 @@ impl Engine {
                         Node::Operator(Operator::Sub) => left - right,
                         Node::Operator(Operator::Mul) => left * right,
                         Node::Operator(Operator::Div) => left / right,
+                        Node::Operator(Operator::Pow) => left.powf(right),
+                        Node::Operator(Operator::Eq) => from_bool(left == right),
+                        Node::Operator(Operator::Ne) => from_bool(left != right),
+                        Node::Operator(Operator::Lt) => from_bool(left < right),
+                        Node::Operator(Operator::Le) => from_bool(left <= right),
+                        Node::Operator(Operator::Gt) => from_bool(left > right),
+                        Node::Operator(Operator::Ge) => from_bool(left >= right),
+                        _ => unreachable!(),
+                    };
+                    stack.push(self.check(k, result, &[left, right])?);
+                }
+                Node::Unary(operator) => {
+                    let value = stack.pop().unwrap();
+                    let result = match operator {
+                        UnaryOperator::Plus => value,
+                        UnaryOperator::Neg => -value,
+                        UnaryOperator::Not => from_bool(!truthy(value)),
+                    };
+                    stack.push(self.check(k, result, &[value])?);
+                }
+                // Only the chosen branch is left on the stack, see `Control::Branch`
+                Node::Function(Function::If) => {}
+                Node::Function(function) => {
+                    let mut args = [0.0; 3];
+                    let args = &mut args[..function.arity()];
+                    for arg in args.iter_mut().rev() {
+                        *arg = stack.pop().unwrap();
+                    }
+
+                    let result = match (function, &args[..]) {
+                        (Function::Min, &[a, b]) => a.min(b),
+                        (Function::Max, &[a, b]) => a.max(b),
+                        (Function::Abs, &[x]) => x.abs(),
+                        (Function::Sqrt, &[x]) => x.sqrt(),
+                        (Function::Exp, &[x]) => x.exp(),
+                        (Function::Ln, &[x]) => x.ln(),
+                        // Not `f64::clamp`, which panics when `lo > hi`
+                        (Function::Clamp, &[x, _, _]) if x.is_nan() => x,
+                        (Function::Clamp, &[x, lo, hi]) => x.max(lo).min(hi),
+                        (Function::IsNull, &[_]) => 0.0,
                         _ => unreachable!(),
                     };
-                    stack.push(result);
+                    stack.push(self.check(k, result, args)?);
                 }
                 _ => {}
             };
+
+            let control = self.controls[k];
+            k += 1;
+
+            match control {
+                None => {}
+                Some(Control::Branch { otherwise }) => {
+                    if !truthy(stack.pop().unwrap()) {
+                        k = otherwise;
+                    }
+                }
+                Some(Control::Jump { to }) => k = to,
+                Some(Control::ShortCircuit { when, to }) => {
+                    let left = truthy(stack.pop().unwrap());
+                    if left == when {
+                        stack.push(from_bool(left));
+                        k = to;
+                    }
+                }
+            }
+            if control.is_some() {
+                operand_index = self.operands_before[k];
+            }
         }
 
-        assert!(stack.len() == 1);
+        debug_assert!(stack.len() == 1);
         Ok(stack.pop().unwrap())
     }
+
+    /// Applies the float policy to the `result` of `node`, when it isn't finite although all of its `args` are
+    #[inline(always)]
+    fn check(&self, k: usize, result: f64, args: &[f64]) -> Result<f64, EvaluationError> {
+        if self.float == FloatPolicy::Propagate || result.is_finite() || args.iter().any(|arg| !arg.is_finite()) {
+            return Ok(result);
+        }
+
+        match self.float {
+            FloatPolicy::Replace(value) => Ok(value),
+            _ => Err(EvaluationError::NonFinite {
+                row: 0,
+                operation: Op::from_node(&self.expression.nodes[k]).unwrap().name(),
+            }),
+        }
+    }
+
+    /// Evaluates a single row where inputs are looked up by variable name
+    pub fn evaluate_named(&self, input: &[(&str, f64)]) -> Result<f64, EvaluationError> {
+        let input = self.expression.resolve(input)?;
+        self.evaluate(&input)
+    }
+}
+
+/// Values are never null here, see `vectorized::Engine::evaluate_nullable`, so `is_null(x)` is 0
+/// and `coalesce(x, y)` is `x`. Neither `x` nor `y` is evaluated or checked then, like in the vectorized engine.
+fn without_nulls(expression: Expression) -> Expression {
+    let Expression {
+        nodes, mut variables, ..
+    } = expression;
+
+    let mut result = Vec::with_capacity(nodes.len());
+    // Start of every operand on the stack, as an index into `result`
+    let mut starts: Vec<usize> = Vec::new();
+    for node in nodes {
+        let first = starts.len() - node.arity();
+        let start = starts.get(first).copied().unwrap_or(result.len());
+        match node {
+            Node::Function(Function::IsNull) => {
+                result.truncate(start);
+                result.push(Node::Literal(0.0));
+            }
+            Node::Function(Function::Coalesce) => result.truncate(starts[first + 1]),
+            node => result.push(node),
+        }
+        starts.truncate(first);
+        starts.push(start);
+    }
+
+    // Variables that were removed keep their position, so the input columns don't change
+    let operands = Expression::operands(&result, &mut variables);
+    let max_stack_depth = Expression::max_stack_depth(&result).expect("the postfix form stays balanced");
+    Expression {
+        nodes: result,
+        variables,
+        operands,
+        max_stack_depth,
+    }
+}
+
+#[inline(always)]
+pub(crate) fn truthy(value: f64) -> bool {
+    value != 0.0
+}
+
+#[inline(always)]
+pub(crate) fn from_bool(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
 }
@@ -1,118 +1,901 @@
-use std::{cmp::Ordering, slice};
+use std::collections::{BTreeMap, HashMap};
+use std::simd::Simd;
+use std::sync::{Arc, Mutex};
 
-use tinyvec::tiny_vec;
+use crate::{Expression, ExpressionSet, Function, Operator, UnaryOperator};
 
-use crate::{Expression, Node, Operator};
+use super::element::{Element, ElementType, FloatPolicy, Overflow};
+use super::explain::{self, OperatorTiming, Timings};
+use super::plan::{Guard, Op, Pipeline, Plan, Slot};
+use super::pool::ThreadPool;
+use super::reduction::{Lanes, Partial, Reducer, Reduction};
+use super::{window, EvaluationError};
 
-use super::EvaluationError;
-
-pub struct Engine {
+/// Evaluates expressions over columns of `T`, `N` rows at a time, see `Expression::to_typed_vectorized_engine`.
+pub struct Engine<T: Element = f64, const N: usize = 4> {
     expression: Expression,
+    pipeline: Pipeline<T>,
+    /// Pipeline of `reduce`, with the validity of windows as a second output, `None` without windows
+    reduced: Option<Pipeline<T>>,
+    /// Pipelines of `evaluate_nullable`, by which input columns have a validity bitmap
+    nullable: Mutex<HashMap<Vec<bool>, Arc<Pipeline<T>>>>,
+    overflow: Overflow,
+    float: FloatPolicy,
+    timings: Option<Timings>,
+}
+
+/// How the plans of a `Pipeline` run over the rows, see the `Engine::evaluate` methods
+#[derive(Clone, Copy, Debug)]
+enum Mode<'a> {
+    Columns,
+    Fused,
+    Parallel(&'a ThreadPool),
+}
+
+/// What `run` reports or replaces, see `Overflow` and `FloatPolicy`
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Checks<T> {
+    pub(crate) overflow: bool,
+    /// NaN or infinity that an operation created from finite arguments
+    pub(crate) non_finite: bool,
+    /// Replaces NaN or infinity that an operation created from finite arguments
+    pub(crate) replacement: Option<T>,
+}
+
+impl<T: Element> Checks<T> {
+    fn new(overflow: Overflow, float: FloatPolicy) -> Self {
+        Self {
+            overflow: overflow == Overflow::Checked,
+            non_finite: float == FloatPolicy::Error,
+            replacement: match float {
+                FloatPolicy::Replace(value) => T::from_f64(value),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Resolved view of a `Slot`, literals are broadcast instead of materialized.
+/// Columns are raw pointers since a step may write its result to a column it reads from.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Column<T> {
+    Ptr(*const T),
+    Splat(T),
 }
 
-impl Engine {
+impl<T: Element> Column<T> {
+    #[inline(always)]
+    fn load<const N: usize>(&self, j: usize) -> Simd<T, N> {
+        match self {
+            Column::Ptr(column) => Simd::from_array(unsafe { column.add(j).cast::<[T; N]>().read_unaligned() }),
+            Column::Splat(value) => Simd::splat(*value),
+        }
+    }
+
+    #[inline(always)]
+    fn get(&self, j: usize) -> T {
+        match self {
+            Column::Ptr(column) => unsafe { *column.add(j) },
+            Column::Splat(value) => *value,
+        }
+    }
+}
+
+impl<T: Element, const N: usize> Engine<T, N> {
+    /// `expression` must have been type checked for `T`, see `element::check`
     pub(crate) fn new(expression: Expression) -> Self {
-        Self { expression }
+        let pipeline = Pipeline::new(&expression);
+        let inputs = expression.required_input_length();
+        let reduced = expression
+            .has_windows()
+            .then(|| Pipeline::nullable(&expression, &vec![None; inputs], inputs));
+        Self {
+            expression,
+            pipeline,
+            reduced,
+            nullable: Mutex::default(),
+            overflow: Overflow::default(),
+            float: FloatPolicy::default(),
+            timings: None,
+        }
     }
 
-    pub fn evaluate(&self, input: &[&[f64]], output: &mut [f64]) -> Result<(), EvaluationError> {
-        if input.len() != self.expression.required_input_length {
-            return Err(EvaluationError::InvalidInputLength(
-                input.len(),
-                self.expression.required_input_length,
-            ));
+    /// What happens when integer arithmetic overflows, checked by default
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// What happens when floating point operations result in NaN or infinity, propagated by default
+    pub fn with_float_policy(mut self, float: FloatPolicy) -> Self {
+        self.float = float;
+        self
+    }
+
+    /// Collects the time every operator takes while evaluating, see `timings`.
+    /// Off by default, since every step of every chunk reads the clock.
+    pub fn with_timings(mut self) -> Self {
+        self.timings = Some(Timings::default());
+        self
+    }
+
+    /// Time spent in each operator by every evaluation so far, by name.
+    /// Empty unless the engine was built `with_timings`.
+    pub fn timings(&self) -> BTreeMap<&'static str, OperatorTiming> {
+        self.timings.as_ref().map(Timings::get).unwrap_or_default()
+    }
+
+    pub fn reset_timings(&self) {
+        if let Some(timings) = &self.timings {
+            timings.reset();
+        }
+    }
+
+    /// The postfix program, its stack depth, the steps that `evaluate` runs with the columns they read and write,
+    /// the scratch columns every plan needs, and how many rows go into a vector and a fused chunk
+    pub fn explain(&self) -> String {
+        let inputs = self.expression.required_input_length();
+        let windows = self
+            .pipeline
+            .stages
+            .iter()
+            .map(|(_, windows)| windows.len())
+            .sum::<usize>();
+        let chunk_rows = chunk_rows::<T, N>(&self.pipeline.last, inputs + windows);
+        explain::explain::<T, N>(&self.expression, &self.pipeline, chunk_rows)
+    }
+
+    /// Evaluates all rows one operation at a time, `input` columns are ordered like `Expression::variables`.
+    /// Null rows of window functions are NaN, or 0 for integers, see `evaluate_nullable`.
+    pub fn evaluate(&self, input: &[&[T]], output: &mut [T]) -> Result<(), EvaluationError> {
+        self.validate(input, output)?;
+        execute_pipeline::<T, N>(
+            &self.pipeline,
+            input,
+            &mut [output],
+            Mode::Columns,
+            self.checks(),
+            self.timings.as_ref(),
+        )
+    }
+
+    /// Same as `evaluate`, but runs the whole expression over one cache-sized chunk of rows at a time.
+    pub fn evaluate_fused(&self, input: &[&[T]], output: &mut [T]) -> Result<(), EvaluationError> {
+        self.validate(input, output)?;
+        execute_pipeline::<T, N>(
+            &self.pipeline,
+            input,
+            &mut [output],
+            Mode::Fused,
+            self.checks(),
+            self.timings.as_ref(),
+        )
+    }
+
+    /// Same as `evaluate_fused`, but rows are split into one partition per thread of `pool`.
+    pub fn evaluate_parallel(
+        &self,
+        input: &[&[T]],
+        output: &mut [T],
+        pool: &ThreadPool,
+    ) -> Result<(), EvaluationError> {
+        self.validate(input, output)?;
+        let mode = Mode::Parallel(pool);
+        execute_pipeline::<T, N>(
+            &self.pipeline,
+            input,
+            &mut [output],
+            mode,
+            self.checks(),
+            self.timings.as_ref(),
+        )
+    }
+
+    /// Same as `evaluate_fused`, with Arrow style validity bitmaps where `None` means that no row is null.
+    /// `output_validity` gets the validity of every output row, the value of a null row is unspecified.
+    pub fn evaluate_nullable(
+        &self,
+        input: &[&[T]],
+        validity: &[Option<&[u8]>],
+        output: &mut [T],
+        output_validity: &mut [u8],
+    ) -> Result<(), EvaluationError> {
+        let count = self.validate(input, output)?;
+        if validity.len() != input.len() {
+            return Err(EvaluationError::InvalidValidityCount(validity.len(), input.len()));
         }
 
-        let expected_count = input[0].len();
-        for (i, &column) in input[1..].iter().enumerate() {
-            if column.len() != expected_count {
-                return Err(EvaluationError::InvalidInputColumnLength(
-                    column.len(),
-                    expected_count,
-                    i,
-                ));
+        let bytes = count.div_ceil(8);
+        for (i, bitmap) in validity.iter().enumerate() {
+            if matches!(bitmap, Some(bitmap) if bitmap.len() < bytes) {
+                return Err(EvaluationError::InvalidValidityLength(bitmap.unwrap().len(), bytes, i));
             }
         }
+        if output_validity.len() < bytes {
+            return Err(EvaluationError::InvalidOutputValidityLength(
+                output_validity.len(),
+                bytes,
+            ));
+        }
 
-        use std::simd::f64x4 as vector;
+        // Validity becomes extra input columns of 1 and 0, so that it's computed by the same steps as values.
+        // Windows need whole columns, otherwise they're filled in one chunk of rows at a time
+        let bitmaps = validity.iter().flatten().collect::<Vec<_>>();
+        let pipeline = self.nullable_pipeline(validity);
+        let chunk_rows = match pipeline.stages.is_empty() {
+            // Chunks start at a whole byte of the bitmaps
+            true => chunk_rows::<T, N>(&pipeline.last, input.len() + bitmaps.len()).next_multiple_of(8 * N),
+            false => count.max(1),
+        };
 
-        let lanes = vector::LEN;
+        let valid = T::from_f64(1.0).unwrap();
+        let mut columns = vec![vec![T::default(); chunk_rows.min(count)]; bitmaps.len()];
+        let mut result_validity = vec![T::default(); chunk_rows.min(count)];
+        output_validity[..bytes].fill(0);
+        for start in (0..count).step_by(chunk_rows) {
+            let rows = chunk_rows.min(count - start);
+            for (column, bitmap) in columns.iter_mut().zip(&bitmaps) {
+                for (j, value) in column[..rows].iter_mut().enumerate() {
+                    *value = if is_set(bitmap, start + j) { valid } else { T::default() };
+                }
+            }
 
-        const MAX_STACK_SIZE: usize = 16;
-        let mut stack = tiny_vec!([usize; MAX_STACK_SIZE]);
+            let chunk = input
+                .iter()
+                .map(|column| &column[start..start + rows])
+                .chain(columns.iter().map(|column| &column[..rows]))
+                .collect::<Vec<_>>();
+            let outputs: &mut [&mut [T]] = &mut [&mut output[start..start + rows], &mut result_validity[..rows]];
+            execute_pipeline::<T, N>(
+                &pipeline,
+                &chunk,
+                outputs,
+                Mode::Fused,
+                self.checks(),
+                self.timings.as_ref(),
+            )
+            .map_err(|error| offset_row(error, start))?;
 
-        let expr = &self.expression.nodes[..];
-        let mut operand_index = 0;
-        for op in expr {
-            match op {
-                Node::Operand => {
-                    stack.push(operand_index);
-                    operand_index += 1;
+            for (j, &value) in result_validity[..rows].iter().enumerate() {
+                if value != T::default() {
+                    output_validity[(start + j) / 8] |= 1 << ((start + j) % 8);
                 }
-                Node::Operator(operator) => {
-                    let right = stack.pop().unwrap();
-                    let left = stack.pop().unwrap();
-
-                    let mut out = output.as_mut_ptr();
-
-                    let left_col = match left.cmp(&input.len()) {
-                        Ordering::Less => input[left],
-                        _ => output,
-                    };
-                    let right_col = match right.cmp(&input.len()) {
-                        Ordering::Less => input[right],
-                        _ => output,
-                    };
-
-                    assert!(left_col.len() == right_col.len());
-
-                    let mut j = 0;
-                    while j < expected_count && expected_count - j >= lanes {
-                        let l = vector::from_slice(&left_col[j..j + 4]);
-                        let r = vector::from_slice(&right_col[j..j + 4]);
-
-                        let result = match operator {
-                            Operator::Add => l + r,
-                            Operator::Sub => l - r,
-                            Operator::Mul => l * r,
-                            Operator::Div => l / r,
-                        };
-
-                        result.copy_to_slice(unsafe { slice::from_raw_parts_mut(out, lanes) });
-                        out = unsafe { out.add(lanes) };
-
-                        j += lanes;
-                    }
-
-                    while j < expected_count {
-                        let l = left_col[j];
-                        let r = right_col[j];
-
-                        let result = match operator {
-                            Operator::Add => l + r,
-                            Operator::Sub => l - r,
-                            Operator::Mul => l * r,
-                            Operator::Div => l / r,
-                        };
-
-                        unsafe {
-                            *out = result;
-                            out = out.add(1);
-                        }
-
-                        j += 1;
-                    }
-
-                    stack.push(input.len());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reduces all rows to a single value one chunk at a time, `None` when there are no rows.
+    /// Null rows of window functions are skipped, an integer sum that overflows is reported at the last row.
+    pub fn reduce(&self, input: &[&[T]], reduction: Reduction) -> Result<Option<f64>, EvaluationError> {
+        let count = self.validate_input(input, 0)?;
+
+        let mut reducer = Reducer::<N>::new(reduction);
+        self.execute_reduced(input, count, |_, values, validity| reducer.update(values, validity))?;
+        self.finish(reducer.partial(), reduction, count)
+    }
+
+    /// Same as `reduce`, but reduces the rows of every distinct value in `keys` separately
+    pub fn reduce_grouped(
+        &self,
+        input: &[&[T]],
+        keys: &[i64],
+        reduction: Reduction,
+    ) -> Result<BTreeMap<i64, Option<f64>>, EvaluationError> {
+        let count = self.validate_input(input, keys.len())?;
+        if keys.len() != count {
+            return Err(EvaluationError::InvalidKeyLength(keys.len(), count));
+        }
+
+        // Rows of a group are scattered, so every group accumulates one row at a time
+        let mut groups: HashMap<i64, Lanes<1>> = HashMap::new();
+        self.execute_reduced(input, count, |offset, values, validity| {
+            for (j, (&key, &value)) in keys[offset..].iter().zip(values).enumerate() {
+                if validity.is_some_and(|validity| validity[j] == T::default()) {
+                    continue;
                 }
-                Node::LeftParens => todo!(),
-                Node::RightParens => todo!(),
+                let group = groups.entry(key).or_insert_with(|| Lanes::new(reduction));
+                group.push(Simd::<T, 1>::splat(value));
             }
+        })?;
+
+        let groups = groups.into_iter();
+        groups
+            .map(|(key, group)| Ok((key, self.finish(group.partial(), reduction, count)?)))
+            .collect()
+    }
+
+    fn finish(&self, partial: Partial, reduction: Reduction, count: usize) -> Result<Option<f64>, EvaluationError> {
+        if self.overflow == Overflow::Checked && partial.overflows(reduction) {
+            return Err(EvaluationError::Overflow {
+                row: count - 1,
+                operation: "sum",
+            });
         }
+        Ok(partial.finish(reduction))
+    }
+
+    /// Evaluates all rows where input columns are looked up by variable name
+    pub fn evaluate_named(&self, input: &[(&str, &[T])], output: &mut [T]) -> Result<(), EvaluationError> {
+        let input = self.expression.resolve(input)?;
+        self.evaluate(&input, output)
+    }
+
+    /// Checks the input and output columns, returning the number of rows
+    fn validate(&self, input: &[&[T]], output: &[T]) -> Result<usize, EvaluationError> {
+        let count = self.validate_input(input, output.len())?;
+        if output.len() != count {
+            return Err(EvaluationError::InvalidOutputLength(output.len(), count));
+        }
+
+        Ok(count)
+    }
+
+    fn validate_input(&self, input: &[&[T]], default_count: usize) -> Result<usize, EvaluationError> {
+        validate_input(input, self.expression.required_input_length(), default_count)
+    }
 
-        assert!(stack.len() == 1);
-        assert!(stack.pop().unwrap() == input.len());
+    fn checks(&self) -> Checks<T> {
+        Checks::new(self.overflow, self.float)
+    }
+
+    /// The pipeline for inputs with these validity bitmaps, built the first time they come up
+    fn nullable_pipeline(&self, validity: &[Option<&[u8]>]) -> Arc<Pipeline<T>> {
+        let shape = validity.iter().map(Option::is_some).collect::<Vec<_>>();
+        let mut pipelines = self.nullable.lock().unwrap();
+        let pipeline = pipelines.entry(shape).or_insert_with(|| {
+            // Bitmaps are the input columns after the values
+            let nullable = validity
+                .iter()
+                .scan(validity.len(), |next, bitmap| {
+                    Some(bitmap.map(|_| {
+                        *next += 1;
+                        *next - 1
+                    }))
+                })
+                .collect::<Vec<_>>();
+            let inputs = validity.len() + validity.iter().flatten().count();
+            Arc::new(Pipeline::nullable(&self.expression, &nullable, inputs))
+        });
+        Arc::clone(pipeline)
+    }
+
+    /// Runs the pipeline over one chunk of rows at a time, passing the row offset, the values of every chunk and,
+    /// for expressions with windows, their validity to `reduce`
+    fn execute_reduced(
+        &self,
+        input: &[&[T]],
+        count: usize,
+        mut reduce: impl FnMut(usize, &[T], Option<&[T]>),
+    ) -> Result<(), EvaluationError> {
+        let pipeline = self.reduced.as_ref().unwrap_or(&self.pipeline);
+        let columns = windows::<T, N>(
+            pipeline,
+            input,
+            count,
+            Mode::Fused,
+            self.checks(),
+            self.timings.as_ref(),
+        )?;
+        let input = with_windows(input, &columns);
+
+        let plan = &pipeline.last;
+        let chunk_rows = chunk_rows::<T, N>(plan, input.len()).min(count).max(1);
+        let mut scratch = vec![T::default(); plan.scratch_columns * chunk_rows];
+        let mut results = vec![vec![T::default(); chunk_rows]; plan.results.len()];
+        for start in (0..count).step_by(chunk_rows) {
+            let rows = chunk_rows.min(count - start);
+            let mut outputs = results.iter_mut().map(|result| &mut result[..rows]).collect::<Vec<_>>();
+            execute::<T, N>(
+                plan,
+                &input,
+                start,
+                &mut outputs,
+                &mut scratch,
+                self.checks(),
+                self.timings.as_ref(),
+            )?;
+            reduce(
+                start,
+                &results[0][..rows],
+                results.get(1).map(|validity| &validity[..rows]),
+            );
+        }
 
         Ok(())
     }
 }
+
+/// Evaluates every expression of an `ExpressionSet` in one pass over the input, sharing common subexpressions.
+pub struct BatchEngine<T: Element = f64, const N: usize = 4> {
+    set: ExpressionSet,
+    pipeline: Pipeline<T>,
+    overflow: Overflow,
+    float: FloatPolicy,
+}
+
+impl<T: Element, const N: usize> BatchEngine<T, N> {
+    /// Every expression of `set` must have been type checked for `T`, see `element::check`
+    pub(crate) fn new(set: ExpressionSet) -> Self {
+        let pipeline = Pipeline::from_expressions(set.expressions(), set.variables().len());
+        Self {
+            set,
+            pipeline,
+            overflow: Overflow::default(),
+            float: FloatPolicy::default(),
+        }
+    }
+
+    /// What happens when integer arithmetic overflows, checked by default
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// What happens when floating point operations result in NaN or infinity, propagated by default
+    pub fn with_float_policy(mut self, float: FloatPolicy) -> Self {
+        self.float = float;
+        self
+    }
+
+    /// Evaluates all rows, writing the result of the expression at index `k` to `outputs[k]`.
+    /// `input` columns are ordered like `ExpressionSet::variables`.
+    pub fn evaluate(&self, input: &[&[T]], outputs: &mut [&mut [T]]) -> Result<(), EvaluationError> {
+        if self.validate(input, outputs)? == 0 {
+            return Ok(());
+        }
+        execute_pipeline::<T, N>(&self.pipeline, input, outputs, Mode::Fused, self.checks(), None)
+    }
+
+    /// Same as `evaluate`, but rows are split into one partition per thread, see `Engine::evaluate_parallel`
+    pub fn evaluate_parallel(
+        &self,
+        input: &[&[T]],
+        outputs: &mut [&mut [T]],
+        pool: &ThreadPool,
+    ) -> Result<(), EvaluationError> {
+        if self.validate(input, outputs)? == 0 {
+            return Ok(());
+        }
+        let mode = Mode::Parallel(pool);
+        execute_pipeline::<T, N>(&self.pipeline, input, outputs, mode, self.checks(), None)
+    }
+
+    fn checks(&self) -> Checks<T> {
+        Checks::new(self.overflow, self.float)
+    }
+
+    /// Checks the input and output columns, returning the number of rows, or 0 when there is nothing to evaluate
+    fn validate(&self, input: &[&[T]], outputs: &[&mut [T]]) -> Result<usize, EvaluationError> {
+        let expressions = self.set.expressions().len();
+        if outputs.len() != expressions {
+            return Err(EvaluationError::InvalidOutputCount(outputs.len(), expressions));
+        }
+
+        let Some(first) = outputs.first() else {
+            return Ok(0);
+        };
+        let count = validate_input(input, self.set.variables().len(), first.len())?;
+        for output in outputs {
+            if output.len() != count {
+                return Err(EvaluationError::InvalidOutputLength(output.len(), count));
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// Checks the input columns, returning the number of rows or `default_count` when there are no columns
+fn validate_input<T>(input: &[&[T]], required: usize, default_count: usize) -> Result<usize, EvaluationError> {
+    if input.len() != required {
+        return Err(EvaluationError::InvalidInputLength(input.len(), required));
+    }
+
+    // Expressions made up of only literals have no input columns to size the output by
+    let expected_count = input.first().map_or(default_count, |column| column.len());
+    for (i, &column) in input.iter().enumerate().skip(1) {
+        if column.len() != expected_count {
+            return Err(EvaluationError::InvalidInputColumnLength(
+                column.len(),
+                expected_count,
+                i,
+            ));
+        }
+    }
+
+    Ok(expected_count)
+}
+
+/// Runs every stage of `pipeline` and computes its windows, then the last plan into `outputs`
+fn execute_pipeline<T: Element, const N: usize>(
+    pipeline: &Pipeline<T>,
+    input: &[&[T]],
+    outputs: &mut [&mut [T]],
+    mode: Mode,
+    checks: Checks<T>,
+    timings: Option<&Timings>,
+) -> Result<(), EvaluationError> {
+    let columns = windows::<T, N>(pipeline, input, outputs[0].len(), mode, checks, timings)?;
+    execute_plan::<T, N>(
+        &pipeline.last,
+        &with_windows(input, &columns),
+        outputs,
+        mode,
+        checks,
+        timings,
+    )
+}
+
+/// The window columns of `pipeline`, each window is computed over all rows of its argument
+fn windows<T: Element, const N: usize>(
+    pipeline: &Pipeline<T>,
+    input: &[&[T]],
+    count: usize,
+    mode: Mode,
+    checks: Checks<T>,
+    timings: Option<&Timings>,
+) -> Result<Vec<Vec<T>>, EvaluationError> {
+    let mut columns: Vec<Vec<T>> = Vec::new();
+    for (plan, windows) in &pipeline.stages {
+        let mut args = vec![vec![T::default(); count]; windows.len()];
+        let mut outputs = args.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>();
+        execute_plan::<T, N>(
+            plan,
+            &with_windows(input, &columns),
+            &mut outputs,
+            mode,
+            checks,
+            timings,
+        )?;
+
+        for (&(function, size), arg) in windows.iter().zip(&args) {
+            let mut column = vec![T::default(); count];
+            Timings::measure(timings, Op::Window(function, size), count, || {
+                window::apply(function, size, arg, &mut column, checks.overflow)
+            })
+            .map_err(|row| EvaluationError::Overflow {
+                row,
+                operation: function.name(),
+            })?;
+            columns.push(column);
+        }
+    }
+
+    Ok(columns)
+}
+
+/// The same error for rows that start at `offset`
+fn offset_row(error: EvaluationError, offset: usize) -> EvaluationError {
+    match error {
+        EvaluationError::Overflow { row, operation } => EvaluationError::Overflow {
+            row: offset + row,
+            operation,
+        },
+        EvaluationError::NonFinite { row, operation } => EvaluationError::NonFinite {
+            row: offset + row,
+            operation,
+        },
+        error => error,
+    }
+}
+
+/// `input` followed by the window columns
+fn with_windows<'a, T>(input: &[&'a [T]], columns: &'a [Vec<T>]) -> Vec<&'a [T]> {
+    input.iter().copied().chain(columns.iter().map(Vec::as_slice)).collect()
+}
+
+fn execute_plan<T: Element, const N: usize>(
+    plan: &Plan<T>,
+    input: &[&[T]],
+    outputs: &mut [&mut [T]],
+    mode: Mode,
+    checks: Checks<T>,
+    timings: Option<&Timings>,
+) -> Result<(), EvaluationError> {
+    match mode {
+        Mode::Columns => {
+            let mut scratch = vec![T::default(); plan.scratch_columns * outputs[0].len()];
+            execute::<T, N>(plan, input, 0, outputs, &mut scratch, checks, timings)
+        }
+        Mode::Fused => execute_chunked::<T, N>(plan, input, 0, outputs, checks, timings),
+        Mode::Parallel(pool) => execute_parallel::<T, N>(plan, input, outputs, pool, checks, timings),
+    }
+}
+
+/// Rows per chunk for `Engine::evaluate_fused`, so that every column a chunk touches fits in L1 together
+pub(crate) fn chunk_rows<T, const N: usize>(plan: &Plan<T>, inputs: usize) -> usize {
+    const CACHE_SIZE: usize = 32 * 1024;
+    const MIN_CHUNK_ROWS: usize = 256;
+
+    let columns = inputs + plan.scratch_columns + plan.results.len();
+    let rows = (CACHE_SIZE / size_of::<T>() / columns).max(MIN_CHUNK_ROWS);
+    // Whole vectors only, so that only the last chunk has a remainder
+    (rows / N * N).max(N)
+}
+
+/// Same as `execute_chunked`, but rows are split into one partition per thread, see `Engine::evaluate_parallel`
+fn execute_parallel<T: Element, const N: usize>(
+    plan: &Plan<T>,
+    input: &[&[T]],
+    outputs: &mut [&mut [T]],
+    pool: &ThreadPool,
+    checks: Checks<T>,
+    timings: Option<&Timings>,
+) -> Result<(), EvaluationError> {
+    let count = outputs[0].len();
+
+    // Partitions start at a chunk boundary, so they are split into the same chunks as on a single thread
+    let chunk_rows = chunk_rows::<T, N>(plan, input.len());
+    let chunks = count.div_ceil(chunk_rows);
+    let partition_rows = chunks.div_ceil(pool.threads()).max(1) * chunk_rows;
+    if partition_rows >= count {
+        return execute_chunked::<T, N>(plan, input, 0, outputs, checks, timings);
+    }
+
+    // The rows of every output that belong to each partition
+    let mut partitions: Vec<Vec<&mut [T]>> = (0..count.div_ceil(partition_rows)).map(|_| Vec::new()).collect();
+    for output in outputs.iter_mut() {
+        for (partition, rows) in partitions.iter_mut().zip(output.chunks_mut(partition_rows)) {
+            partition.push(rows);
+        }
+    }
+
+    let tasks = partitions
+        .into_iter()
+        .enumerate()
+        .map(|(k, mut outputs)| {
+            Box::new(move || execute_chunked::<T, N>(plan, input, k * partition_rows, &mut outputs, checks, timings))
+                as Box<dyn FnOnce() -> Result<(), EvaluationError> + Send>
+        })
+        .collect();
+
+    // The error of the first partition that failed, same as on a single thread
+    pool.run(tasks).into_iter().collect()
+}
+
+/// Runs the plan over one chunk of the rows in `outputs` at a time, see `Engine::evaluate_fused`
+fn execute_chunked<T: Element, const N: usize>(
+    plan: &Plan<T>,
+    input: &[&[T]],
+    offset: usize,
+    outputs: &mut [&mut [T]],
+    checks: Checks<T>,
+    timings: Option<&Timings>,
+) -> Result<(), EvaluationError> {
+    let count = outputs[0].len();
+    let chunk_rows = chunk_rows::<T, N>(plan, input.len()).min(count).max(1);
+    let mut scratch = vec![T::default(); plan.scratch_columns * chunk_rows];
+    for start in (0..count).step_by(chunk_rows) {
+        let end = count.min(start + chunk_rows);
+        let mut chunk = outputs
+            .iter_mut()
+            .map(|output| &mut output[start..end])
+            .collect::<Vec<_>>();
+        execute::<T, N>(plan, input, offset + start, &mut chunk, &mut scratch, checks, timings)?;
+    }
+
+    Ok(())
+}
+
+/// Runs every step of `plan` over validated columns, for the rows starting at `offset` that `outputs` hold.
+fn execute<T: Element, const N: usize>(
+    plan: &Plan<T>,
+    input: &[&[T]],
+    offset: usize,
+    outputs: &mut [&mut [T]],
+    scratch: &mut [T],
+    checks: Checks<T>,
+    timings: Option<&Timings>,
+) -> Result<(), EvaluationError> {
+    let count = outputs[0].len();
+    debug_assert!(outputs.len() == plan.results.len());
+    debug_assert!(scratch.len() >= plan.scratch_columns * count);
+    debug_assert!(input.iter().all(|column| column.len() >= offset + count));
+
+    let output_ptrs = outputs.iter_mut().map(|output| output.as_mut_ptr()).collect::<Vec<_>>();
+    let scratch_ptr = scratch.as_mut_ptr();
+    let column = |slot: Slot<T>| match slot {
+        Slot::Input(i) => input[i][offset..].as_ptr().cast_mut(),
+        Slot::Scratch(i) => unsafe { scratch_ptr.add(i * count) },
+        Slot::Output(k) => output_ptrs[k],
+        Slot::Literal(_) => unreachable!(),
+    };
+    let resolve = |slot: Slot<T>| match slot {
+        Slot::Literal(value) => Column::Splat(value),
+        slot => Column::Ptr(column(slot)),
+    };
+
+    for step in &plan.steps {
+        let mut args = [Column::Splat(T::default()); 3];
+        for (arg, slot) in args.iter_mut().zip(&step.args) {
+            *arg = resolve(*slot);
+        }
+
+        let guard = step.guard.as_ref().map(|guard| {
+            let conjunction =
+                |all: &Vec<(Slot<T>, bool)>| all.iter().map(|&(slot, truthy)| (resolve(slot), truthy)).collect();
+            guard.iter().map(conjunction).collect::<Vec<_>>()
+        });
+        let result = Timings::measure(timings, step.op, count, || {
+            run::<T, N>(
+                step.op,
+                &args[..step.args.len()],
+                column(step.dst),
+                count,
+                checks,
+                guard.as_ref(),
+            )
+        });
+        if let Err(row) = result {
+            let (row, operation) = (offset + row, step.op.name());
+            // Floating point never overflows, and integers are always finite
+            return Err(match T::TYPE {
+                ElementType::I64 => EvaluationError::Overflow { row, operation },
+                ElementType::F32 | ElementType::F64 => EvaluationError::NonFinite { row, operation },
+            });
+        }
+    }
+
+    for (k, &result) in plan.results.iter().enumerate() {
+        // Only lone variables or literals, and values that are also the result of another output,
+        // haven't been written to their output yet
+        let source = match result {
+            Slot::Output(j) if j == k => continue,
+            Slot::Literal(value) => {
+                unsafe { std::slice::from_raw_parts_mut(output_ptrs[k], count) }.fill(value);
+                continue;
+            }
+            slot => column(slot),
+        };
+        unsafe { std::ptr::copy(source, output_ptrs[k], count) };
+    }
+
+    Ok(())
+}
+
+fn is_set(bitmap: &[u8], j: usize) -> bool {
+    bitmap[j / 8] & (1 << (j % 8)) != 0
+}
+
+/// Computes `op` for `count` rows of `args` into `out`, returning the first row that fails one of the `checks`.
+pub(crate) fn run<T: Element, const N: usize>(
+    op: Op,
+    args: &[Column<T>],
+    out: *mut T,
+    count: usize,
+    checks: Checks<T>,
+    guard: Option<&Guard<Column<T>>>,
+) -> Result<(), usize> {
+    match op {
+        Op::Unary(operator) => {
+            let args = [args[0]];
+            match operator {
+                UnaryOperator::Plus => apply::<T, N, 1>(args, out, count, checks, guard, |[v], _| v),
+                UnaryOperator::Neg => apply::<T, N, 1>(args, out, count, checks, guard, |[v], o| T::neg(v, o)),
+                UnaryOperator::Not => apply::<T, N, 1>(args, out, count, checks, guard, |[v], _| T::not(v)),
+            }
+        }
+        Op::Binary(operator) => {
+            let args = [args[0], args[1]];
+            match operator {
+                Operator::Add => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], o| T::add(l, r, o)),
+                Operator::Sub => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], o| T::sub(l, r, o)),
+                Operator::Mul => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], o| T::mul(l, r, o)),
+                Operator::Div => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], o| T::div(l, r, o)),
+                Operator::Pow => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], o| T::pow(l, r, o)),
+                Operator::Eq => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::equal(l, r)),
+                Operator::Ne => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::not_equal(l, r)),
+                Operator::Lt => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::less(l, r)),
+                Operator::Le => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::less_equal(l, r)),
+                Operator::Gt => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::greater(l, r)),
+                Operator::Ge => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::greater_equal(l, r)),
+                // Both sides are always evaluated, there is nothing to gain from branching per row
+                Operator::And => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::and(l, r)),
+                Operator::Or => apply::<T, N, 2>(args, out, count, checks, guard, |[l, r], _| T::or(l, r)),
+            }
+        }
+        // Small constant powers such as `x ^ 2` become a couple of multiplications
+        Op::Powi(n) => apply::<T, N, 1>([args[0]], out, count, checks, guard, |[x], o| T::powi(x, n, o)),
+        Op::Function(function) => match function {
+            Function::Abs => apply::<T, N, 1>([args[0]], out, count, checks, guard, |[x], o| T::abs(x, o)),
+            Function::Sqrt => apply::<T, N, 1>([args[0]], out, count, checks, guard, |[x], _| T::sqrt(x)),
+            Function::Exp => apply::<T, N, 1>([args[0]], out, count, checks, guard, |[x], _| T::exp(x)),
+            Function::Ln => apply::<T, N, 1>([args[0]], out, count, checks, guard, |[x], _| T::ln(x)),
+            Function::Min => apply::<T, N, 2>([args[0], args[1]], out, count, checks, guard, |[a, b], _| T::min(a, b)),
+            Function::Max => apply::<T, N, 2>([args[0], args[1]], out, count, checks, guard, |[a, b], _| T::max(a, b)),
+            Function::Clamp => apply::<T, N, 3>(
+                [args[0], args[1], args[2]],
+                out,
+                count,
+                checks,
+                guard,
+                |[x, lo, hi], _| T::clamp(x, lo, hi),
+            ),
+            // Both branches are evaluated, the condition only selects between them
+            Function::If => apply::<T, N, 3>(
+                [args[0], args[1], args[2]],
+                out,
+                count,
+                checks,
+                guard,
+                |[c, a, b], _| T::select(c, a, b),
+            ),
+            // Nulls are handled by the plan, so this is only reached for literals, which are never null
+            Function::Coalesce => apply::<T, N, 2>([args[0], args[1]], out, count, checks, guard, |[x, _], _| x),
+            Function::IsNull => {
+                apply::<T, N, 1>([args[0]], out, count, checks, guard, |_, _| Simd::splat(T::default()))
+            }
+            Function::Lag | Function::Lead | Function::RollingSum | Function::RollingMean => {
+                unreachable!("windows are computed between the stages of a pipeline")
+            }
+        },
+        Op::Window(..) => unreachable!("windows are computed between the stages of a pipeline"),
+    }
+}
+
+/// Applies `op` lane-wise to `args`, `out` may be one of them. Failed checks are ignored where `guard` doesn't hold.
+#[inline(always)]
+fn apply<T: Element, const N: usize, const A: usize>(
+    args: [Column<T>; A],
+    out: *mut T,
+    count: usize,
+    checks: Checks<T>,
+    guard: Option<&Guard<Column<T>>>,
+    op: impl Fn([Simd<T, N>; A], &mut bool) -> Simd<T, N>,
+) -> Result<(), usize> {
+    // The result, and whether any lane failed a check
+    let check_floats = checks.non_finite || checks.replacement.is_some();
+    let evaluate = |args: [Simd<T, N>; A]| {
+        let mut overflow = false;
+        let mut result = op(args, &mut overflow);
+        let mut failed = checks.overflow && overflow;
+        if check_floats {
+            failed |= T::non_finite(&args, &mut result, checks.replacement) && checks.non_finite;
+        }
+        (result, failed)
+    };
+    // Computes a single row in the first lane of a splat, so that it goes through the exact same operations
+    let row = |j: usize| {
+        let (result, failed) = evaluate(args.map(|column| Simd::splat(column.get(j))));
+        (result[0], failed)
+    };
+    let used = |j: usize| {
+        let holds = |all: &Vec<(Column<T>, bool)>| {
+            all.iter()
+                .all(|(column, truthy)| (column.get(j) != T::default()) == *truthy)
+        };
+        guard.is_none_or(|guard| guard.iter().any(holds))
+    };
+    let fails = |j: &usize| row(*j).1 && used(*j);
+
+    let mut j = 0;
+    while j < count && count - j >= N {
+        let (result, failed) = evaluate(args.map(|column| column.load(j)));
+        // Only now is it worth finding out which of the rows it was
+        if failed {
+            if let Some(j) = (j..j + N).find(fails) {
+                return Err(j);
+            }
+        }
+
+        unsafe { out.add(j).cast::<[T; N]>().write_unaligned(result.to_array()) };
+
+        j += N;
+    }
+
+    // The remainder that doesn't fill a vector
+    while j < count {
+        let (result, failed) = row(j);
+        if failed && fails(&j) {
+            return Err(j);
+        }
+
+        unsafe { *out.add(j) = result };
+
+        j += 1;
+    }
+
+    Ok(())
+}
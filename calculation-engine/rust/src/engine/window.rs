@@ -0,0 +1,135 @@
+use std::simd::Simd;
+
+use crate::Function;
+
+use super::element::Element;
+
+/// Computes the window `function` of `size` rows over the column `x`, 0 where it reaches past either end.
+/// When `checked`, returns the first row where a sum overflows.
+pub(crate) fn apply<T: Element>(
+    function: Function,
+    size: usize,
+    x: &[T],
+    out: &mut [T],
+    checked: bool,
+) -> Result<(), usize> {
+    let count = x.len();
+    let shift = size.min(count);
+    match function {
+        Function::Lag => {
+            out[..shift].fill(T::default());
+            out[shift..].copy_from_slice(&x[..count - shift]);
+        }
+        Function::Lead => {
+            out[..count - shift].copy_from_slice(&x[shift..]);
+            out[count - shift..].fill(T::default());
+        }
+        Function::RollingSum => rolling_sum(size, x, out, checked)?,
+        Function::RollingMean => {
+            rolling_sum(size, x, out, checked)?;
+            let size = Simd::<T, 1>::splat(T::from_f64(size as f64).unwrap());
+            for value in out.iter_mut() {
+                *value = T::div(Simd::splat(*value), size, &mut false)[0];
+            }
+        }
+        _ => unreachable!("{function} is not a window function"),
+    }
+
+    Ok(())
+}
+
+/// Sums of `size` consecutive rows from suffix and prefix sums of blocks, so that no rows are subtracted.
+fn rolling_sum<T: Element>(size: usize, x: &[T], out: &mut [T], checked: bool) -> Result<(), usize> {
+    let add = |l: T, r: T, overflow: &mut bool| T::add(Simd::<T, 1>::splat(l), Simd::splat(r), overflow)[0];
+
+    // Sums from each row to the end of its block, and for each block the number of rows at its start
+    // whose sum has overflowed, which only matters when a window that includes them is computed
+    let mut suffixes = vec![T::default(); x.len()];
+    let mut overflowed = vec![0; x.len().div_ceil(size)];
+    for ((block, suffix), overflowed) in x.chunks(size).zip(suffixes.chunks_mut(size)).zip(&mut overflowed) {
+        let mut sum = T::default();
+        let mut overflow = false;
+        for (i, (value, suffix)) in block.iter().zip(suffix.iter_mut()).enumerate().rev() {
+            sum = add(sum, *value, &mut overflow);
+            *suffix = sum;
+            if overflow && *overflowed == 0 {
+                *overflowed = i + 1;
+            }
+        }
+    }
+
+    // Sums from the start of each block to each row, added to the rest of the window at the end of the block before
+    for (k, block) in x.chunks(size).enumerate() {
+        let mut prefix = T::default();
+        let mut prefix_overflow = false;
+        for (i, value) in block.iter().enumerate() {
+            let row = k * size + i;
+            prefix = add(prefix, *value, &mut prefix_overflow);
+
+            let mut overflow = prefix_overflow;
+            out[row] = if k == 0 || i + 1 == size {
+                // All rows so far, or exactly this block
+                prefix
+            } else {
+                overflow |= i + 1 < overflowed[k - 1];
+                add(suffixes[row + 1 - size], prefix, &mut overflow)
+            };
+            if checked && overflow {
+                return Err(row);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(function: Function, size: usize, x: &[f64]) -> Vec<f64> {
+        let mut out = vec![f64::NAN; x.len()];
+        apply(function, size, x, &mut out, true).unwrap();
+        out
+    }
+
+    #[test]
+    fn shifts() {
+        let x = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(window(Function::Lag, 1, &x), [0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(window(Function::Lead, 3, &x), [4.0, 0.0, 0.0, 0.0]);
+        assert_eq!(window(Function::Lag, 0, &x), x);
+        assert_eq!(window(Function::Lag, 5, &x), [0.0; 4]);
+    }
+
+    #[test]
+    fn rolling_sums() {
+        let x = (1..=10).map(f64::from).collect::<Vec<_>>();
+        for size in 1..=11 {
+            let expected = (0..x.len())
+                .map(|j| x[(j + 1).saturating_sub(size)..=j].iter().sum::<f64>())
+                .collect::<Vec<_>>();
+            assert_eq!(window(Function::RollingSum, size, &x), expected, "size {size}");
+        }
+        assert_eq!(window(Function::RollingMean, 2, &x[..3]), [0.5, 1.5, 2.5]);
+    }
+
+    #[test]
+    fn values_leave_the_window() {
+        let x = [1.0, f64::INFINITY, 1.0, 1.0, 1.0];
+        assert_eq!(
+            window(Function::RollingSum, 2, &x),
+            [1.0, f64::INFINITY, f64::INFINITY, 2.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let mut out = [0; 3];
+        assert_eq!(
+            apply(Function::RollingSum, 2, &[1, i64::MAX, 1], &mut out, true),
+            Err(1)
+        );
+        assert!(apply(Function::RollingSum, 2, &[1, i64::MAX, 1], &mut out, false).is_ok());
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use super::{AlignedBuffer, Batch, InputError};
+use crate::{Expression, Node};
+
+/// Reads the columns of a CSV file that a header row names, one `Batch` at a time. Empty fields are null.
+pub struct CsvReader<R, T = f64> {
+    reader: R,
+    variables: Vec<String>,
+    /// Index of the field that holds each variable
+    fields: Vec<usize>,
+    header_len: usize,
+    chunk_rows: usize,
+    /// Rows read so far
+    row: usize,
+    line: String,
+    record: Vec<String>,
+    element: PhantomData<T>,
+}
+
+impl<R: BufRead, T: Copy + Default + FromStr> CsvReader<R, T> {
+    pub const DEFAULT_CHUNK_ROWS: usize = 64 * 1024;
+
+    /// Reads the header and maps its names to the variables of `expression`, columns that aren't named are skipped.
+    /// Expressions with window functions are rejected, since batches are evaluated on their own.
+    pub fn new(reader: R, expression: &Expression) -> Result<Self, InputError> {
+        let window = expression.nodes.iter().find_map(|node| match node {
+            Node::Function(function) if function.is_window() => Some(*function),
+            _ => None,
+        });
+        if let Some(function) = window {
+            return Err(InputError::UnsupportedWindow(function));
+        }
+
+        let variables = expression.variables();
+        let mut csv = Self {
+            reader,
+            variables: variables.to_vec(),
+            fields: Vec::new(),
+            header_len: 0,
+            chunk_rows: Self::DEFAULT_CHUNK_ROWS,
+            row: 0,
+            line: String::new(),
+            record: Vec::new(),
+            element: PhantomData,
+        };
+
+        if !csv.read_record()? {
+            return Err(InputError::MissingHeader);
+        }
+        csv.header_len = csv.record.len();
+        let header = &csv.record;
+        csv.fields = variables
+            .iter()
+            .map(|variable| {
+                let field = header.iter().position(|name| name.trim() == variable);
+                field.ok_or_else(|| InputError::MissingColumn(variable.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(csv)
+    }
+
+    /// Largest number of rows in a batch
+    pub fn with_chunk_rows(mut self, rows: usize) -> Self {
+        self.chunk_rows = rows.max(1);
+        self
+    }
+
+    /// The next rows, `None` once all of them have been read
+    pub fn next_batch(&mut self) -> Result<Option<Batch<T>>, InputError> {
+        let offset = self.row;
+        let mut columns = (0..self.fields.len())
+            .map(|_| AlignedBuffer::with_capacity(self.chunk_rows))
+            .collect::<Vec<_>>();
+        // Rows that are null in each column, bitmaps are only built for columns that have any
+        let mut nulls: Vec<Vec<usize>> = vec![Vec::new(); self.fields.len()];
+
+        while self.row - offset < self.chunk_rows && self.read_record()? {
+            // A blank line is a null row when there is a single column, unless it's the last line of the file
+            if self.line.trim().is_empty() && (self.header_len > 1 || self.reader.fill_buf()?.is_empty()) {
+                continue;
+            }
+            if self.record.len() != self.header_len {
+                return Err(InputError::InvalidRowLength {
+                    row: self.row,
+                    found: self.record.len(),
+                    expected: self.header_len,
+                });
+            }
+
+            for (k, &field) in self.fields.iter().enumerate() {
+                let text = self.record[field].trim();
+                if text.is_empty() {
+                    nulls[k].push(self.row - offset);
+                    columns[k].push(T::default());
+                    continue;
+                }
+                match text.parse() {
+                    Ok(value) => columns[k].push(value),
+                    Err(_) => {
+                        return Err(InputError::InvalidValue {
+                            row: self.row,
+                            column: self.variables[k].clone(),
+                            value: text.to_string(),
+                        })
+                    }
+                }
+            }
+            self.row += 1;
+        }
+
+        let len = self.row - offset;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let validity = nulls
+            .iter()
+            .map(|nulls| {
+                if nulls.is_empty() {
+                    return None;
+                }
+                let mut bitmap = AlignedBuffer::filled(len.div_ceil(8));
+                for j in 0..len {
+                    bitmap[j / 8] |= 1 << (j % 8);
+                }
+                for &j in nulls {
+                    bitmap[j / 8] &= !(1 << (j % 8));
+                }
+                Some(bitmap)
+            })
+            .collect();
+
+        Ok(Some(Batch {
+            columns,
+            validity,
+            offset,
+            len,
+        }))
+    }
+
+    /// Splits the next record into `record`, `false` at the end of the input.
+    /// A quoted field may span several lines.
+    fn read_record(&mut self) -> Result<bool, InputError> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(false);
+        }
+
+        self.record.clear();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut start = 0;
+        loop {
+            let mut chars = self.line[start..].chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if quoted => quoted = false,
+                    '"' if field.trim().is_empty() => {
+                        field.clear();
+                        quoted = true;
+                    }
+                    ',' if !quoted => self.record.push(std::mem::take(&mut field)),
+                    '\r' | '\n' if !quoted => {}
+                    c => field.push(c),
+                }
+            }
+            if !quoted {
+                break;
+            }
+
+            start = self.line.len();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Err(InputError::UnterminatedQuote(self.row));
+            }
+        }
+        self.record.push(field);
+
+        Ok(true)
+    }
+}
+
+impl<R: BufRead, T: Copy + Default + FromStr> Iterator for CsvReader<R, T> {
+    type Item = Result<Batch<T>, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
+    }
+}
+
+/// Writes a single CSV column, e.g. the output of an engine, one chunk of rows at a time.
+/// Null rows are written as empty fields, the same way `CsvReader` reads them.
+pub struct CsvWriter<W> {
+    writer: W,
+    name: String,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Writes the header, the column is called `name`
+    pub fn new(mut writer: W, name: &str) -> io::Result<Self> {
+        if name.contains([',', '"', '\n', '\r']) {
+            writeln!(writer, "\"{}\"", name.replace('"', "\"\""))?;
+        } else {
+            writeln!(writer, "{name}")?;
+        }
+        Ok(Self {
+            writer,
+            name: name.to_string(),
+        })
+    }
+
+    /// Appends rows, where `validity` is a bitmap like the output validity of `vectorized::Engine::evaluate_nullable`
+    pub fn write<T: Display>(&mut self, values: &[T], validity: Option<&[u8]>) -> Result<(), InputError> {
+        let bytes = values.len().div_ceil(8);
+        if let Some(validity) = validity.filter(|validity| validity.len() < bytes) {
+            return Err(InputError::InvalidValidityLength(
+                self.name.clone(),
+                validity.len(),
+                bytes,
+            ));
+        }
+        for (j, value) in values.iter().enumerate() {
+            match validity {
+                Some(validity) if validity[j / 8] & (1 << (j % 8)) == 0 => writeln!(self.writer)?,
+                _ => writeln!(self.writer, "{value}")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes and returns the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Function;
+
+    /// Reader for the variables of `source`
+    fn reader<'a, T: Copy + Default + FromStr>(
+        csv: &'a str,
+        source: &str,
+    ) -> Result<CsvReader<&'a [u8], T>, InputError> {
+        CsvReader::new(csv.as_bytes(), &Expression::parse(source).unwrap())
+    }
+
+    #[test]
+    fn chunks() {
+        let csv = "id,\"price\",qty\n1,2.5,4\n2,1.5,\n3,\"10\",2\r\n4,0.5,8\n5,3,1\n";
+        let expression = Expression::parse("price * coalesce(qty, 1)").unwrap();
+        let reader = CsvReader::<_, f64>::new(csv.as_bytes(), &expression)
+            .unwrap()
+            .with_chunk_rows(2);
+        let engine = expression.to_vectorized_engine();
+        let mut writer = CsvWriter::new(Vec::new(), "total").unwrap();
+        let mut offsets = Vec::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            offsets.push(batch.offset());
+            assert!(batch
+                .columns()
+                .iter()
+                .all(|column| (column.as_ptr() as usize).is_multiple_of(64)));
+
+            let mut output = vec![0.0; batch.len()];
+            let mut validity = vec![0; batch.len().div_ceil(8)];
+            engine
+                .evaluate_nullable(&batch.columns(), &batch.validity(), &mut output, &mut validity)
+                .unwrap();
+            writer.write(&output, Some(&validity)).unwrap();
+        }
+
+        assert_eq!(offsets, [0, 2, 4]);
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "total\n10\n1.5\n20\n4\n3\n");
+    }
+
+    #[test]
+    fn nulls_and_quotes() {
+        let csv = "a,b\n\"1\",\n,\"x\"\"\ny\"\n 3 ,4\n";
+        let mut csv = reader::<i64>(csv, "a").unwrap();
+        let batch = csv.next_batch().unwrap().unwrap();
+        assert_eq!(batch.columns(), [&[1, 0, 3][..]]);
+        assert_eq!(batch.validity(), [Some(&[0b101][..])]);
+        assert!(csv.next_batch().unwrap().is_none());
+
+        let mut writer = CsvWriter::new(Vec::new(), "say \"hi\"").unwrap();
+        writer.write(&[1, 2, 3], Some(&[0b110])).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "\"say \"\"hi\"\"\"\n\n2\n3\n");
+
+        // Blank lines are skipped when there are several columns, and null rows of a single one but the last
+        let mut csv = reader::<i64>("a,b\n1,2\n\n3,4\n\n", "b").unwrap();
+        let batch = csv.next_batch().unwrap().unwrap();
+        assert_eq!(batch.columns(), [&[2, 4][..]]);
+        assert_eq!(batch.validity(), [None]);
+        let mut csv = reader::<i64>("a\n1\n\n3\n\n", "a").unwrap();
+        let batch = csv.next_batch().unwrap().unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.validity(), [Some(&[0b101][..])]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(reader::<f64>("", "a"), Err(InputError::MissingHeader)));
+        assert!(matches!(
+            reader::<f64>("a,b\n", "c"),
+            Err(InputError::MissingColumn(name)) if name == "c"
+        ));
+
+        let mut csv = reader::<f64>("a,b\n1,2\n3,x\n", "b").unwrap();
+        assert!(matches!(
+            csv.next_batch(),
+            Err(InputError::InvalidValue { row: 1, column, value }) if column == "b" && value == "x"
+        ));
+        let mut csv = reader::<i64>("a\n1.5\n", "a").unwrap();
+        assert!(matches!(csv.next_batch(), Err(InputError::InvalidValue { row: 0, .. })));
+        let mut csv = reader::<f64>("a,b\n1,2,3\n", "a").unwrap();
+        assert!(matches!(
+            csv.next_batch(),
+            Err(InputError::InvalidRowLength {
+                row: 0,
+                found: 3,
+                expected: 2
+            })
+        ));
+        let mut csv = reader::<f64>("a\n\"1\n", "a").unwrap();
+        assert!(matches!(csv.next_batch(), Err(InputError::UnterminatedQuote(0))));
+        assert!(matches!(
+            reader::<f64>("a\n1\n", "a - lag(a)"),
+            Err(InputError::UnsupportedWindow(Function::Lag))
+        ));
+
+        let mut writer = CsvWriter::new(Vec::new(), "total").unwrap();
+        assert!(matches!(
+            writer.write(&[0; 9], Some(&[0xff])),
+            Err(InputError::InvalidValidityLength(name, 1, 2)) if name == "total"
+        ));
+    }
+}
@@ -0,0 +1,296 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use thiserror::Error;
+
+use crate::Function;
+
+mod csv;
+
+pub use csv::{CsvReader, CsvWriter};
+
+#[derive(Error, Debug)]
+pub enum InputError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the input has no header")]
+    MissingHeader,
+    #[error("no column was provided for variable '{0}'")]
+    MissingColumn(String),
+    #[error("row {row} has {found} fields but the header has {expected}")]
+    InvalidRowLength { row: usize, found: usize, expected: usize },
+    #[error("quoted field is not terminated at row {0}")]
+    UnterminatedQuote(usize),
+    #[error("'{value}' in column '{column}' at row {row} is not a valid number")]
+    InvalidValue { row: usize, column: String, value: String },
+    #[error("column '{0}' has {1} rows instead of {2}")]
+    InvalidColumnLength(String, usize, usize),
+    #[error("validity of column '{0}' has {1} bytes instead of at least {2}")]
+    InvalidValidityLength(String, usize, usize),
+    #[error("window function {0} would only see the rows of its own batch")]
+    UnsupportedWindow(Function),
+}
+
+/// Alignment of every buffer, a cache line and the widest SIMD registers
+pub const ALIGNMENT: usize = 64;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct CacheLine([u8; ALIGNMENT]);
+
+/// Growable buffer of `T` that starts at a 64 byte boundary.
+pub struct AlignedBuffer<T> {
+    lines: Vec<CacheLine>,
+    len: usize,
+    element: PhantomData<T>,
+}
+
+impl<T: Copy + Default> AlignedBuffer<T> {
+    const ELEMENTS_PER_LINE: usize = {
+        assert!(size_of::<T>() != 0 && ALIGNMENT.is_multiple_of(size_of::<T>()) && align_of::<T>() <= ALIGNMENT);
+        ALIGNMENT / size_of::<T>()
+    };
+
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            lines: Vec::with_capacity(capacity.div_ceil(Self::ELEMENTS_PER_LINE)),
+            len: 0,
+            element: PhantomData,
+        }
+    }
+
+    /// `len` elements of `T::default()`
+    pub fn filled(len: usize) -> Self {
+        let mut buffer = Self::with_capacity(len);
+        buffer.resize(len, T::default());
+        buffer
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.len == self.lines.len() * Self::ELEMENTS_PER_LINE {
+            self.lines.push(CacheLine([0; ALIGNMENT]));
+        }
+        self.len += 1;
+        let last = self.len - 1;
+        self[last] = value;
+    }
+
+    pub fn resize(&mut self, len: usize, value: T) {
+        self.lines
+            .resize(len.div_ceil(Self::ELEMENTS_PER_LINE), CacheLine([0; ALIGNMENT]));
+        let old = self.len.min(len);
+        self.len = len;
+        self[old..].fill(value);
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.len = 0;
+    }
+}
+
+impl<T: Copy + Default> Default for AlignedBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy + Default> From<&[T]> for AlignedBuffer<T> {
+    fn from(values: &[T]) -> Self {
+        let mut buffer = Self::filled(values.len());
+        buffer.copy_from_slice(values);
+        buffer
+    }
+}
+
+impl<T: Copy + Default> Clone for AlignedBuffer<T> {
+    fn clone(&self) -> Self {
+        Self::from(&self[..])
+    }
+}
+
+impl<T> Deref for AlignedBuffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // Every cache line holds a whole number of `T`, see `ELEMENTS_PER_LINE`
+        unsafe { std::slice::from_raw_parts(self.lines.as_ptr().cast(), self.len) }
+    }
+}
+
+impl<T> DerefMut for AlignedBuffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.lines.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for AlignedBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// A column of an Arrow style record batch, with a validity bitmap in the same layout as
+/// `vectorized::Engine::evaluate_nullable`, where `None` means no row is null
+#[derive(Clone, Copy, Debug)]
+pub struct Field<'a, T> {
+    pub name: &'a str,
+    pub values: &'a [T],
+    pub validity: Option<&'a [u8]>,
+}
+
+/// Rows of input columns in aligned buffers, one for each variable of an expression in the same order,
+/// ready for `vectorized::Engine::evaluate` and `evaluate_nullable`
+#[derive(Clone, Debug)]
+pub struct Batch<T: Copy + Default> {
+    columns: Vec<AlignedBuffer<T>>,
+    validity: Vec<Option<AlignedBuffer<u8>>>,
+    /// Row of the whole input that the first row of this batch is
+    offset: usize,
+    len: usize,
+}
+
+impl<T: Copy + Default> Batch<T> {
+    /// Copies the fields that `variables` name, e.g. `Expression::variables`. Fields that aren't named are ignored.
+    pub fn from_fields(variables: &[String], fields: &[Field<'_, T>]) -> Result<Self, InputError> {
+        let fields = variables
+            .iter()
+            .map(|variable| match fields.iter().find(|field| field.name == variable) {
+                Some(field) => Ok(field),
+                None => Err(InputError::MissingColumn(variable.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let len = fields.first().map_or(0, |field| field.values.len());
+        let bytes = len.div_ceil(8);
+        for field in &fields {
+            if field.values.len() != len {
+                return Err(InputError::InvalidColumnLength(
+                    field.name.to_string(),
+                    field.values.len(),
+                    len,
+                ));
+            }
+            if matches!(field.validity, Some(validity) if validity.len() < bytes) {
+                let found = field.validity.unwrap().len();
+                return Err(InputError::InvalidValidityLength(field.name.to_string(), found, bytes));
+            }
+        }
+
+        Ok(Self {
+            columns: fields.iter().map(|field| AlignedBuffer::from(field.values)).collect(),
+            validity: fields
+                .iter()
+                .map(|field| field.validity.map(|validity| AlignedBuffer::from(&validity[..bytes])))
+                .collect(),
+            offset: 0,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Row of the whole input that the first row of this batch is, for batches that were read in chunks
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Input columns in the order of the variables
+    pub fn columns(&self) -> Vec<&[T]> {
+        self.columns.iter().map(|column| &column[..]).collect()
+    }
+
+    /// Validity bitmaps in the order of the variables, `None` for columns without nulls
+    pub fn validity(&self) -> Vec<Option<&[u8]>> {
+        self.validity
+            .iter()
+            .map(|validity| validity.as_ref().map(|validity| &validity[..]))
+            .collect()
+    }
+
+    pub fn has_nulls(&self) -> bool {
+        self.validity.iter().any(Option::is_some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Expression;
+
+    #[test]
+    fn aligned_buffers() {
+        let mut buffer = AlignedBuffer::<f64>::new();
+        for i in 0..20 {
+            buffer.push(i as f64);
+        }
+        assert_eq!(buffer.as_ptr() as usize % ALIGNMENT, 0);
+        assert_eq!(buffer[..], (0..20).map(|i| i as f64).collect::<Vec<_>>());
+
+        buffer.resize(3, 0.0);
+        buffer.resize(5, -1.0);
+        assert_eq!(buffer[..], [0.0, 1.0, 2.0, -1.0, -1.0]);
+
+        let bytes = AlignedBuffer::<u8>::from(&[1, 2, 3][..]);
+        assert_eq!(bytes.as_ptr() as usize % ALIGNMENT, 0);
+        assert_eq!(bytes.clone()[..], [1, 2, 3]);
+    }
+
+    #[test]
+    fn record_batches() {
+        let expression = Expression::parse("coalesce(b, 0) * a").unwrap();
+        let fields = [
+            Field {
+                name: "c",
+                values: &[9.0, 9.0, 9.0][..],
+                validity: None,
+            },
+            Field {
+                name: "a",
+                values: &[1.0, 2.0, 3.0],
+                validity: None,
+            },
+            Field {
+                name: "b",
+                values: &[4.0, 5.0, 6.0],
+                validity: Some(&[0b101]),
+            },
+        ];
+        let batch = Batch::from_fields(expression.variables(), &fields).unwrap();
+        assert_eq!(batch.columns(), [&[4.0, 5.0, 6.0][..], &[1.0, 2.0, 3.0]]);
+        assert!(batch.has_nulls());
+
+        let engine = expression.clone().to_vectorized_engine();
+        let mut output = vec![0.0; batch.len()];
+        let mut output_validity = [0];
+        engine
+            .evaluate_nullable(&batch.columns(), &batch.validity(), &mut output, &mut output_validity)
+            .unwrap();
+        assert_eq!(output, [4.0, 0.0, 18.0]);
+
+        let missing = Batch::from_fields(&["d".to_string()], &fields);
+        assert!(matches!(missing, Err(InputError::MissingColumn(name)) if name == "d"));
+        let short = [
+            fields[1],
+            Field {
+                values: &[1.0],
+                ..fields[2]
+            },
+        ];
+        assert!(matches!(
+            Batch::from_fields(expression.variables(), &short),
+            Err(InputError::InvalidColumnLength(name, 3, 1)) if name == "a"
+        ));
+    }
+}
@@ -1,16 +1,35 @@
-#![feature(let_chains)]
 #![feature(portable_simd)]
-#![feature(iter_repeat_n)]
+
+use std::ops::Range;
 
 use thiserror::Error;
 
+use engine::element::{Element, ElementType};
+
+mod builder;
+mod derivative;
+pub mod differential;
+mod display;
 pub mod engine;
+pub mod input;
+mod optimizer;
+mod parser;
 mod precedence;
+mod set;
+
+pub use builder::{col, lit, when, Formula, Then, When};
+pub use set::ExpressionSet;
 
 #[derive(Clone, Debug)]
 pub struct Expression {
     pub(crate) nodes: Vec<Node>,
-    pub(crate) required_input_length: usize,
+    /// Distinct variable names, in order of first appearance.
+    /// This is the order engines expect their input columns in.
+    pub(crate) variables: Vec<String>,
+    /// Index into `variables` for every variable node in `nodes`, in postfix order
+    pub(crate) operands: Vec<usize>,
+    /// Largest number of values on the stack at any point while evaluating `nodes`
+    pub(crate) max_stack_depth: usize,
 }
 
 #[derive(Error, Debug, PartialEq, Eq)]
@@ -19,105 +38,592 @@ pub enum ExpressionConstructionError {
     EmptyExpression,
     #[error("was unable to match a set of parens in expression")]
     UbalancedParens,
+    #[error("unknown token in expression")]
+    UnknownToken,
+    #[error("operator is missing its right operand")]
+    DanglingOperator,
+    #[error("expected an operand")]
+    MissingOperand,
+    #[error("expected an operator between operands")]
+    MissingOperator,
+    #[error("unknown function")]
+    UnknownFunction,
+    #[error("function is not followed by an argument list")]
+    MissingArgumentList,
+    #[error("argument separator outside of a function call")]
+    MisplacedSeparator,
+    #[error("function {function} takes {expected} argument(s) but {found} were given")]
+    InvalidArity {
+        function: Function,
+        expected: usize,
+        found: usize,
+    },
+    #[error("literal can not be represented as {0}")]
+    UnrepresentableLiteral(ElementType),
+    #[error("function {function} is not supported for {element}")]
+    UnsupportedFunction { function: Function, element: ElementType },
+    #[error("exponents must be non-negative integer literals for {0}")]
+    InvalidExponent(ElementType),
+    #[error("window size must be an integer literal of at least {0}")]
+    InvalidWindowSize(usize),
+    #[error("operator does not have enough operands")]
+    StackUnderflow,
+    #[error("expression leaves {0} values instead of a single result")]
+    UnbalancedStack(usize),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("{error} at {}..{}", span.start, span.end)]
+pub struct ParseError {
+    #[source]
+    pub error: ExpressionConstructionError,
+    /// Byte range in the source text where the problem was found
+    pub span: Range<usize>,
 }
 
 impl Expression {
+    /// Parses a formula such as `a + (b - c) * d`
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        parser::parse(source)
+    }
+
     pub fn from_infix(expression: &[Node]) -> Result<Self, ExpressionConstructionError> {
+        Self::from_infix_at(expression).map_err(|(error, _)| error)
+    }
+
+    /// Same as `from_infix`, but errors also carry the index of the offending node.
+    /// An index of `expression.len()` refers to the end of the expression.
+    pub(crate) fn from_infix_at(expression: &[Node]) -> Result<Self, (ExpressionConstructionError, usize)> {
         if expression.is_empty() {
-            return Err(ExpressionConstructionError::EmptyExpression);
+            return Err((ExpressionConstructionError::EmptyExpression, 0));
         }
 
-        let mut result: Vec<Node> = Vec::with_capacity(expression.len());
-        let mut stack: Vec<Node> = Vec::with_capacity(expression.len());
+        // Postfix nodes with the index of the infix node they come from
+        let mut result: Vec<(Node, usize)> = Vec::with_capacity(expression.len());
+        let mut stack: Vec<(Node, usize)> = Vec::with_capacity(expression.len());
+        // One entry per open parens, the number of arguments seen so far if it belongs to a function call
+        let mut parens: Vec<Option<usize>> = Vec::new();
+
+        // Tracks whether the next node should start an operand (value or left parens)
+        // or continue after one (operator or right parens)
+        let mut expect_operand = true;
+
+        for (i, op) in expression.iter().enumerate() {
+            let follows_function = i > 0 && matches!(expression[i - 1], Node::Function(_));
+            if follows_function && *op != Node::LeftParens {
+                return Err((ExpressionConstructionError::MissingArgumentList, i - 1));
+            }
 
-        for op in expression {
             match op {
-                Node::Operand => result.push(*op),
-                Node::LeftParens => stack.push(*op),
-                Node::RightParens => {
-                    while let Some(n) = stack.last()
-                        && *n != Node::LeftParens
-                    {
-                        result.push(stack.pop().unwrap());
+                Node::Variable(_) | Node::Literal(_) => {
+                    if !expect_operand {
+                        return Err((ExpressionConstructionError::MissingOperator, i));
+                    }
+                    expect_operand = false;
+                    result.push((op.clone(), i));
+                }
+                Node::Function(_) => {
+                    if !expect_operand {
+                        return Err((ExpressionConstructionError::MissingOperator, i));
+                    }
+                    stack.push((op.clone(), i));
+                }
+                Node::LeftParens => {
+                    if !expect_operand {
+                        return Err((ExpressionConstructionError::MissingOperator, i));
+                    }
+                    parens.push(follows_function.then_some(1));
+                    stack.push((op.clone(), i));
+                }
+                Node::Separator => {
+                    if !matches!(parens.last(), Some(Some(_))) {
+                        return Err((ExpressionConstructionError::MisplacedSeparator, i));
+                    }
+                    if expect_operand {
+                        return Err(Self::missing_operand(expression, i));
                     }
 
-                    if let Some(n) = stack.last()
-                        && *n != Node::LeftParens
-                    {
-                        return Err(ExpressionConstructionError::UbalancedParens);
+                    while matches!(stack.last(), Some((n, _)) if *n != Node::LeftParens) {
+                        result.push(stack.pop().unwrap());
                     }
 
-                    match stack.last() {
-                        Some(Node::LeftParens) => stack.pop().unwrap(),
-                        _ => return Err(ExpressionConstructionError::UbalancedParens),
+                    if let Some(Some(arguments)) = parens.last_mut() {
+                        *arguments += 1;
+                    }
+                    expect_operand = true;
+                }
+                Node::RightParens => {
+                    let arguments = match parens.pop() {
+                        Some(arguments) => arguments,
+                        None => return Err((ExpressionConstructionError::UbalancedParens, i)),
                     };
+
+                    // `f()`, which is only wrong because no function takes zero arguments
+                    let empty_call = arguments.is_some() && expression[i - 1] == Node::LeftParens;
+                    if expect_operand && !empty_call {
+                        return Err(Self::missing_operand(expression, i));
+                    }
+
+                    loop {
+                        match stack.pop() {
+                            Some((Node::LeftParens, _)) => break,
+                            Some(entry) => result.push(entry),
+                            None => return Err((ExpressionConstructionError::UbalancedParens, i)),
+                        }
+                    }
+
+                    if let Some(arguments) = arguments {
+                        let arguments = if empty_call { 0 } else { arguments };
+                        match stack.pop() {
+                            Some((Node::Function(function), j)) => {
+                                // A left out last argument, e.g. `lag(x)` for `lag(x, 1)`
+                                let default = function
+                                    .default_argument()
+                                    .filter(|_| arguments + 1 == function.arity());
+                                if let Some(value) = default {
+                                    result.push((Node::Literal(value), j));
+                                } else if arguments != function.arity() {
+                                    let error = ExpressionConstructionError::InvalidArity {
+                                        function,
+                                        expected: function.arity(),
+                                        found: arguments,
+                                    };
+                                    return Err((error, j));
+                                }
+                                result.push((Node::Function(function), j));
+                            }
+                            _ => unreachable!("call parens are always preceded by their function"),
+                        }
+                    }
+
+                    expect_operand = false;
                 }
+                // A prefix operator in front of an operand, e.g. `-a`, `a * -b` or `not a`
+                Node::Operator(Operator::Add | Operator::Sub) | Node::Unary(_) if expect_operand => match op {
+                    Node::Operator(Operator::Sub) | Node::Unary(UnaryOperator::Neg) => {
+                        stack.push((Node::Unary(UnaryOperator::Neg), i))
+                    }
+                    Node::Unary(UnaryOperator::Not) => stack.push((op.clone(), i)),
+                    // Unary plus is the identity, so it never makes it to the postfix form
+                    _ => {}
+                },
+                Node::Unary(_) => return Err((ExpressionConstructionError::MissingOperator, i)),
                 Node::Operator(_) => {
-                    let prec = precedence::precedence(*op);
-                    while let Some(n) = stack.last()
-                        && prec <= precedence::precedence(*n)
-                    {
-                        result.push(stack.pop().unwrap());
+                    if expect_operand {
+                        return Err((ExpressionConstructionError::MissingOperand, i));
                     }
+                    expect_operand = true;
 
-                    stack.push(*op);
+                    let prec = precedence::precedence(op);
+                    let right_associative = precedence::is_right_associative(op);
+                    while let Some((n, _)) = stack.last() {
+                        let top = precedence::precedence(n);
+                        if prec < top || (prec == top && !right_associative) {
+                            result.push(stack.pop().unwrap());
+                        } else {
+                            break;
+                        }
+                    }
+
+                    stack.push((op.clone(), i));
                 }
             };
         }
 
-        while let Some(n) = stack.pop() {
+        if matches!(expression.last(), Some(Node::Function(_))) {
+            return Err((ExpressionConstructionError::MissingArgumentList, expression.len() - 1));
+        }
+        if expect_operand {
+            return Err(Self::missing_operand(expression, expression.len()));
+        }
+
+        while let Some((n, i)) = stack.pop() {
             if n == Node::LeftParens {
-                return Err(ExpressionConstructionError::UbalancedParens);
+                return Err((ExpressionConstructionError::UbalancedParens, i));
             }
-            result.push(n);
+            result.push((n, i));
         }
 
-        let required_input_length = result.iter().filter(|&n| n == &Node::Operand).count();
+        // Only window sizes can still be invalid, errors point at the infix node of the postfix one
+        let (result, sources): (Vec<Node>, Vec<usize>) = result.into_iter().unzip();
+        Self::from_postfix_at(result).map_err(|(error, k)| (error, sources[k]))
+    }
+
+    /// Builds an expression from nodes that are already in postfix order, e.g. `a b c - +`
+    pub fn from_postfix(expression: Vec<Node>) -> Result<Self, ExpressionConstructionError> {
+        Self::from_postfix_at(expression).map_err(|(error, _)| error)
+    }
+
+    fn from_postfix_at(result: Vec<Node>) -> Result<Self, (ExpressionConstructionError, usize)> {
+        let max_stack_depth = Self::max_stack_depth(&result)?;
+        Self::check_windows(&result)?;
+
+        let mut variables: Vec<String> = Vec::new();
+        let operands = Self::operands(&result, &mut variables);
 
         Ok(Expression {
             nodes: result,
-            required_input_length,
+            variables,
+            operands,
+            max_stack_depth,
         })
     }
 
+    /// Checks that the size of every window is a literal, which is the node right before the function.
+    /// Errors point at that node.
+    fn check_windows(result: &[Node]) -> Result<(), (ExpressionConstructionError, usize)> {
+        for (i, node) in result.iter().enumerate() {
+            let Node::Function(function) = node else {
+                continue;
+            };
+            if !function.is_window() {
+                continue;
+            }
+
+            let min = function.min_window_size();
+            let valid = |size: f64| size.fract() == 0.0 && size >= min as f64 && size <= u32::MAX as f64;
+            if !matches!(result[i - 1], Node::Literal(size) if valid(size)) {
+                return Err((ExpressionConstructionError::InvalidWindowSize(min), i - 1));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether any row depends on other rows, see `Function::is_window`
+    pub(crate) fn has_windows(&self) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| matches!(node, Node::Function(function) if function.is_window()))
+    }
+
+    /// Checks that every operator has its operands and that exactly one value is left in the end,
+    /// which is what allows the engines to size their stacks up front and pop without checking.
+    pub(crate) fn max_stack_depth(result: &[Node]) -> Result<usize, (ExpressionConstructionError, usize)> {
+        if result.is_empty() {
+            return Err((ExpressionConstructionError::EmptyExpression, 0));
+        }
+
+        let mut depth = 0usize;
+        let mut max_stack_depth = 0;
+        for (i, node) in result.iter().enumerate() {
+            match node {
+                Node::LeftParens | Node::RightParens => return Err((ExpressionConstructionError::UbalancedParens, i)),
+                Node::Separator => return Err((ExpressionConstructionError::MisplacedSeparator, i)),
+                _ => {}
+            }
+
+            depth = match depth.checked_sub(node.arity()) {
+                Some(depth) => depth + 1,
+                None => return Err((ExpressionConstructionError::StackUnderflow, i)),
+            };
+            max_stack_depth = max_stack_depth.max(depth);
+        }
+        if depth != 1 {
+            return Err((ExpressionConstructionError::UnbalancedStack(depth), result.len()));
+        }
+
+        Ok(max_stack_depth)
+    }
+
+    /// Index into `variables` for every variable node, names that aren't there yet are added
+    pub(crate) fn operands(result: &[Node], variables: &mut Vec<String>) -> Vec<usize> {
+        result
+            .iter()
+            .filter_map(|n| match n {
+                Node::Variable(name) => Some(name),
+                _ => None,
+            })
+            .map(|name| match variables.iter().position(|v| v == name) {
+                Some(i) => i,
+                None => {
+                    variables.push(name.clone());
+                    variables.len() - 1
+                }
+            })
+            .collect()
+    }
+
+    /// Folds constant subexpressions and removes operations that don't change the result, such as `x * 1`.
+    /// Variables that are optimized away are still part of `variables()`, and null rows stay null.
+    pub fn optimize(self) -> Self {
+        optimizer::optimize(self)
+    }
+
+    /// Partial derivative with respect to `variable`, simplified with `optimize`.
+    /// Takes the same inputs as this expression, and is 0 for variables that it doesn't depend on.
+    pub fn derivative(&self, variable: &str) -> Expression {
+        derivative::derivative(self, variable)
+    }
+
+    /// Distinct input variables, in the order engines expect their inputs
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    pub fn required_input_length(&self) -> usize {
+        self.variables.len()
+    }
+
+    /// Maps named inputs onto the order of `variables()`.
+    /// Inputs that are not referenced by the expression are ignored.
+    pub(crate) fn resolve<T: Copy + Default>(
+        &self,
+        input: &[(&str, T)],
+    ) -> Result<tinyvec::TinyVec<[T; 16]>, engine::EvaluationError> {
+        self.variables
+            .iter()
+            .map(|variable| match input.iter().find(|(name, _)| name == variable) {
+                Some(&(_, value)) => Ok(value),
+                None => Err(engine::EvaluationError::MissingInput(variable.clone())),
+            })
+            .collect()
+    }
+
+    /// An operand was expected before the node at index `i`,
+    /// blame the preceding operator if there is one.
+    fn missing_operand(expression: &[Node], i: usize) -> (ExpressionConstructionError, usize) {
+        match i.checked_sub(1).map(|prev| (prev, &expression[prev])) {
+            Some((prev, Node::Operator(_) | Node::Unary(_))) => (ExpressionConstructionError::DanglingOperator, prev),
+            _ => (ExpressionConstructionError::MissingOperand, i),
+        }
+    }
+
     pub fn to_scalar_engine(self) -> engine::scalar::Engine {
         engine::scalar::Engine::new(self)
     }
 
+    /// Engine that evaluates rows like `to_scalar_engine`, compiled into closures up front
+    pub fn to_compiled_engine(self) -> engine::compiled::Engine {
+        engine::compiled::Engine::new(self)
+    }
+
     pub fn to_vectorized_engine(self) -> engine::vectorized::Engine {
         engine::vectorized::Engine::new(self)
     }
+
+    /// Vectorized engine over columns of `T` that evaluates `N` rows at a time, e.g. `f32` and 16 lanes
+    pub fn to_typed_vectorized_engine<T: Element, const N: usize>(
+        self,
+    ) -> Result<engine::vectorized::Engine<T, N>, ExpressionConstructionError> {
+        self.check_type::<T>()?;
+        Ok(engine::vectorized::Engine::new(self))
+    }
+
+    /// Checks that every literal and operation can be evaluated on `T`,
+    /// integers have no `sqrt`, `exp`, `ln` and only non-negative integer exponents
+    pub fn check_type<T: Element>(&self) -> Result<(), ExpressionConstructionError> {
+        engine::element::check::<T>(self)
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Node {
-    Operand,
+    /// An input column, every occurrence of the same name reads the same column
+    Variable(String),
+    /// A constant, broadcast to every row
+    Literal(f64),
     LeftParens,
     RightParens,
     Operator(Operator),
+    /// Prefix operator, `Operator::Add` and `Operator::Sub` in operand position are treated as these
+    Unary(UnaryOperator),
+    /// Function call, must be followed by its arguments in parens
+    Function(Function),
+    /// Separates the arguments of a function call
+    Separator,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-#[repr(u8)]
+impl Node {
+    /// Number of operands a node consumes in postfix form
+    pub(crate) fn arity(&self) -> usize {
+        match self {
+            Node::Unary(_) => 1,
+            Node::Operator(_) => 2,
+            Node::Function(function) => function.arity(),
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Operator {
-    Add = b'+',
-    Sub = b'-',
-    Mul = b'*',
-    Div = b'/',
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Exponentiation, right associative: `a ^ b ^ c` is `a ^ (b ^ c)`
+    Pow,
+    /// Comparisons evaluate to 1 when true and 0 when false
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Logical operators treat any non-zero value as true and evaluate to 1 or 0
+    And,
+    Or,
+}
+
+impl Operator {
+    /// How the operator is written in a formula
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Sub => "-",
+            Operator::Mul => "*",
+            Operator::Div => "/",
+            Operator::Pow => "^",
+            Operator::Eq => "==",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+            Operator::And => "and",
+            Operator::Or => "or",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum UnaryOperator {
+    Plus = b'+',
+    Neg = b'-',
+    Not = b'!',
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Function {
+    /// `min(a, b)`
+    Min,
+    /// `max(a, b)`
+    Max,
+    /// `abs(x)`
+    Abs,
+    /// `sqrt(x)`
+    Sqrt,
+    /// `exp(x)`
+    Exp,
+    /// `ln(x)`, the natural logarithm
+    Ln,
+    /// `clamp(x, lo, hi)`, same as `min(max(x, lo), hi)` except that NaN stays NaN
+    Clamp,
+    /// `if(condition, then, otherwise)`, the condition is true when non-zero
+    If,
+    /// `coalesce(x, fallback)`, `x` unless it is null
+    Coalesce,
+    /// `is_null(x)`, 1 when `x` is null and 0 otherwise
+    IsNull,
+    /// `lag(x, n)`, the value of `x` `n` rows before, null for the first `n` rows. `n` is 1 when left out.
+    /// Outside of `evaluate_nullable` null rows of windows are NaN, or 0 for integers
+    Lag,
+    /// `lead(x, n)`, the value of `x` `n` rows after, null for the last `n` rows. `n` is 1 when left out
+    Lead,
+    /// `rolling_sum(x, n)`, the sum of `x` over the last `n` rows, null for the first `n - 1` rows
+    RollingSum,
+    /// `rolling_mean(x, n)`, the mean of `x` over the last `n` rows, null for the first `n - 1` rows
+    RollingMean,
+}
+
+impl Function {
+    pub const ALL: [Function; 14] = [
+        Function::Min,
+        Function::Max,
+        Function::Abs,
+        Function::Sqrt,
+        Function::Exp,
+        Function::Ln,
+        Function::Clamp,
+        Function::If,
+        Function::Coalesce,
+        Function::IsNull,
+        Function::Lag,
+        Function::Lead,
+        Function::RollingSum,
+        Function::RollingMean,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Abs => "abs",
+            Function::Sqrt => "sqrt",
+            Function::Exp => "exp",
+            Function::Ln => "ln",
+            Function::Clamp => "clamp",
+            Function::If => "if",
+            Function::Coalesce => "coalesce",
+            Function::IsNull => "is_null",
+            Function::Lag => "lag",
+            Function::Lead => "lead",
+            Function::RollingSum => "rolling_sum",
+            Function::RollingMean => "rolling_mean",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Function> {
+        Function::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    /// Number of arguments the function takes
+    pub fn arity(self) -> usize {
+        match self {
+            Function::Abs | Function::Sqrt | Function::Exp | Function::Ln | Function::IsNull => 1,
+            Function::Min | Function::Max | Function::Coalesce => 2,
+            Function::Lag | Function::Lead | Function::RollingSum | Function::RollingMean => 2,
+            Function::Clamp | Function::If => 3,
+        }
+    }
+
+    /// Value of the last argument when a call leaves it out
+    pub fn default_argument(self) -> Option<f64> {
+        match self {
+            Function::Lag | Function::Lead => Some(1.0),
+            _ => None,
+        }
+    }
+
+    /// Whether the function reads other rows than the current one.
+    /// The last argument of a window function is its size, which has to be an integer literal.
+    pub fn is_window(self) -> bool {
+        matches!(
+            self,
+            Function::Lag | Function::Lead | Function::RollingSum | Function::RollingMean
+        )
+    }
+
+    /// Smallest window size the function accepts
+    fn min_window_size(self) -> usize {
+        match self {
+            Function::RollingSum | Function::RollingMean => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use engine::pool::ThreadPool;
 
     fn get_simple_expression_nodes() -> Vec<Node> {
         vec![
-            Node::Operand,
+            Node::Variable("a".into()),
             Node::Operator(Operator::Add),
             Node::LeftParens,
-            Node::Operand,
+            Node::Variable("b".into()),
             Node::Operator(Operator::Sub),
-            Node::Operand,
+            Node::Variable("c".into()),
             Node::RightParens,
         ]
     }
@@ -144,6 +650,54 @@ mod tests {
         assert_eq!(err, ExpressionConstructionError::UbalancedParens);
     }
 
+    #[test]
+    fn dangling_operator() {
+        let mut nodes = get_simple_expression_nodes();
+        nodes.push(Node::Operator(Operator::Mul));
+
+        let expression = Expression::from_infix(&nodes);
+        assert!(expression.is_err());
+        let err = expression.unwrap_err();
+        assert_eq!(err, ExpressionConstructionError::DanglingOperator);
+    }
+
+    #[test]
+    fn unbalanced_postfix() {
+        let a = || Node::Variable("a".into());
+        let add = || Node::Operator(Operator::Add);
+
+        let expression = Expression::from_postfix(vec![a(), add(), a()]);
+        assert_eq!(expression.unwrap_err(), ExpressionConstructionError::StackUnderflow);
+
+        let expression = Expression::from_postfix(vec![a(), a(), a(), add()]);
+        assert_eq!(expression.unwrap_err(), ExpressionConstructionError::UnbalancedStack(2));
+
+        let expression = Expression::from_postfix(vec![a(), Node::LeftParens]);
+        assert_eq!(expression.unwrap_err(), ExpressionConstructionError::UbalancedParens);
+
+        let expression = Expression::from_postfix(vec![a(), a(), add()]).unwrap();
+        assert_eq!(expression.max_stack_depth, 2);
+        assert_eq!(expression.to_scalar_engine().evaluate(&[2.0]).unwrap(), 4.0);
+    }
+
+    #[test]
+    fn deep_expressions() {
+        // Right nested, so every `a` is on the stack before the first addition
+        let depth = 40;
+        let source = format!("{}a{}", "a + (".repeat(depth), ")".repeat(depth));
+        let expression = Expression::parse(&source).unwrap();
+        assert_eq!(expression.max_stack_depth, depth + 1);
+
+        let result = expression.clone().to_scalar_engine().evaluate(&[1.0]);
+        assert_eq!(result.unwrap(), (depth + 1) as f64);
+
+        let engine = expression.to_vectorized_engine();
+        let a = vec![1.0; 7];
+        let mut output = vec![0.0; a.len()];
+        assert!(engine.evaluate(&[&a], &mut output).is_ok());
+        assert!(output.iter().all(|&value| value == (depth + 1) as f64));
+    }
+
     #[test]
     fn scalar() {
         let nodes = get_simple_expression_nodes();
@@ -182,4 +736,1161 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn literals() {
+        let expression = Expression::parse("price * 1.25 - 1").unwrap();
+        assert_eq!(expression.required_input_length(), 1);
+
+        let engine = expression.clone().to_scalar_engine();
+        assert_eq!(engine.evaluate(&[4.0]).unwrap(), 4.0);
+
+        let engine = expression.to_vectorized_engine();
+        let price = (0..9).map(|i| i as f64).collect::<Vec<_>>();
+        let mut output = vec![0.0; price.len()];
+        assert!(engine.evaluate(&[&price], &mut output).is_ok());
+        let expected = price.iter().map(|p| p * 1.25 - 1.0).collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn only_literals() {
+        let expression = Expression::parse("(1 + 2) * 3").unwrap();
+        assert_eq!(expression.required_input_length(), 0);
+
+        let engine = expression.clone().to_scalar_engine();
+        assert_eq!(engine.evaluate(&[]).unwrap(), 9.0);
+
+        let engine = expression.to_vectorized_engine();
+        let mut output = vec![0.0; 5];
+        assert!(engine.evaluate(&[], &mut output).is_ok());
+        assert_eq!(output, [9.0; 5]);
+    }
+
+    #[test]
+    fn single_operand() {
+        let engine = Expression::parse("a").unwrap().to_vectorized_engine();
+        let a = [1.0, 2.0, 3.0];
+        let mut output = vec![0.0; 3];
+        assert!(engine.evaluate(&[&a], &mut output).is_ok());
+        assert_eq!(output, a);
+
+        let engine = Expression::parse("7").unwrap().to_vectorized_engine();
+        assert!(engine.evaluate(&[], &mut output).is_ok());
+        assert_eq!(output, [7.0; 3]);
+    }
+
+    #[test]
+    fn power_and_negation() {
+        let engine = Expression::parse("-a ^ 2 + 2 ^ 3 ^ 2 - -b ^ 0.5")
+            .unwrap()
+            .to_scalar_engine();
+        let f = |a: f64, b: f64| -a.powf(2.0) + 2f64.powf(3f64.powf(2.0)) + b.powf(0.5);
+        assert_eq!(engine.evaluate(&[3.0, 4.0]).unwrap(), f(3.0, 4.0));
+
+        let expression = Expression::parse("-(2 ^ 3 ^ 2 - (a - b) ^ 3) * 0.5 + a").unwrap();
+        let f = |a: f64, b: f64| -(2f64.powf(3f64.powf(2.0)) - (a - b).powf(3.0)) * 0.5 + a;
+
+        let engine = expression.to_vectorized_engine();
+        let a = (0..11).map(|i| i as f64 * 1.5).collect::<Vec<_>>();
+        let b = (0..11).map(|i| i as f64 - 5.0).collect::<Vec<_>>();
+        let mut output = vec![0.0; a.len()];
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        let expected = a.iter().zip(&b).map(|(&a, &b)| f(a, b)).collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn vectorized_integer_powers() {
+        let x = [0.5, 1.1, -2.3, 3.7, 1e20, 1e-20, -0.0, f64::NAN, f64::INFINITY];
+        for source in ["x ^ -2 * x", "x ^ 2 - x ^ -1", "x ^ 3", "x ^ -16", "x ^ 0"] {
+            let expression = Expression::parse(source).unwrap();
+            let scalar = expression.clone().to_scalar_engine();
+            let mut output = vec![0.0; x.len()];
+            expression.to_vectorized_engine().evaluate(&[&x], &mut output).unwrap();
+            for (&x, output) in x.iter().zip(output) {
+                let expected = scalar.evaluate(&[x]).unwrap();
+                assert_eq!(output.to_bits(), expected.to_bits(), "{source} at {x}");
+            }
+        }
+    }
+
+    #[test]
+    fn missing_argument_list() {
+        let nodes = [
+            Node::Function(Function::Abs),
+            Node::Variable("a".into()),
+            Node::Operator(Operator::Add),
+            Node::Literal(1.0),
+        ];
+
+        let expression = Expression::from_infix(&nodes);
+        assert!(expression.is_err());
+        let err = expression.unwrap_err();
+        assert_eq!(err, ExpressionConstructionError::MissingArgumentList);
+    }
+
+    #[test]
+    fn functions() {
+        let expression = Expression::parse("max(a, 0) + sqrt(abs(b)) * clamp(a, -1, 1) - exp(min(a, b))").unwrap();
+        let f = |a: f64, b: f64| a.max(0.0) + b.abs().sqrt() * a.clamp(-1.0, 1.0) - a.min(b).exp();
+
+        let engine = expression.to_scalar_engine();
+        assert_eq!(engine.evaluate(&[-2.0, -9.0]).unwrap(), f(-2.0, -9.0));
+        assert_eq!(engine.evaluate(&[0.5, 4.0]).unwrap(), f(0.5, 4.0));
+
+        let engine = Expression::parse("ln(sqrt(exp(clamp(max(a, b), 0, 5)))) * 2")
+            .unwrap()
+            .to_vectorized_engine();
+        let a = (0..13).map(|i| i as f64 - 6.0).collect::<Vec<_>>();
+        let b = (0..13).map(|i| 3.0 - i as f64 * 0.25).collect::<Vec<_>>();
+        let mut output = vec![0.0; a.len()];
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        let expected = a
+            .iter()
+            .zip(&b)
+            .map(|(&a, &b)| a.max(b).clamp(0.0, 5.0).exp().sqrt().ln() * 2.0)
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        let expression =
+            Expression::parse("(a > b) + (a >= b) * 2 + (a == b) * 4 + (a != b) * 8 + (a < b and b > 0) * 16").unwrap();
+        let f = |a: f64, b: f64| {
+            let bit = |v: bool, n: f64| if v { n } else { 0.0 };
+            bit(a > b, 1.0) + bit(a >= b, 2.0) + bit(a == b, 4.0) + bit(a != b, 8.0) + bit(a < b && b > 0.0, 16.0)
+        };
+
+        let engine = expression.to_scalar_engine();
+        for (a, b) in [(1.0, 2.0), (2.0, 1.0), (2.0, 2.0), (-3.0, -1.0), (f64::NAN, 1.0)] {
+            assert_eq!(engine.evaluate(&[a, b]).unwrap(), f(a, b), "a = {a}, b = {b}");
+        }
+    }
+
+    #[test]
+    fn scalar_short_circuit() {
+        // The untaken branches would produce NaN if they were evaluated and leaked into the result
+        let engine = Expression::parse("if(x > 0, sqrt(x), -x) + (x < 0 or ln(x) > 0) * 10")
+            .unwrap()
+            .to_scalar_engine();
+        assert_eq!(engine.evaluate(&[4.0]).unwrap(), 2.0 + 10.0);
+        assert_eq!(engine.evaluate(&[-4.0]).unwrap(), 4.0 + 10.0);
+        assert_eq!(engine.evaluate(&[0.5]).unwrap(), 0.5f64.sqrt());
+
+        let engine = Expression::parse("if(a and b, c, if(not a, d, e))")
+            .unwrap()
+            .to_scalar_engine();
+        assert_eq!(engine.evaluate(&[1.0, 1.0, 3.0, 4.0, 5.0]).unwrap(), 3.0);
+        assert_eq!(engine.evaluate(&[0.0, 1.0, 3.0, 4.0, 5.0]).unwrap(), 4.0);
+        assert_eq!(engine.evaluate(&[1.0, 0.0, 3.0, 4.0, 5.0]).unwrap(), 5.0);
+
+        // Without nulls the fallback of `coalesce` and the argument of `is_null` aren't checked
+        let engine = Expression::parse("coalesce(x, ln(x)) + is_null(ln(x)) + y")
+            .unwrap()
+            .to_scalar_engine()
+            .with_float_policy(engine::element::FloatPolicy::Error);
+        assert_eq!(engine.evaluate(&[0.0, 1.0]).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn compiled() {
+        let sources = [
+            "a + (b - c)",
+            "a * 2 - 3 / b + c ^ 2",
+            "-a + +b - not c",
+            "(a > b) + (a >= b) * 2 + (a == b) * 4 + (a != b) * 8 + (a <= c) * 16 + (a < c) * 32",
+            "if(a > 0, sqrt(a), -a) + (a < 0 or ln(a) > 0) * 10",
+            "if(a and b, c, if(not a, 2, 3))",
+            "min(a, b) + max(b, c) * abs(a - c) + exp(c / 10)",
+            "clamp(a, b, c) + clamp(a, c, b)",
+            "coalesce(a, b) + is_null(c)",
+            "2 ^ 3 + 1 / 0 * 0 - a",
+        ];
+        let values = [0.0, 1.0, -2.5, 3.0, f64::NAN, f64::INFINITY];
+
+        for source in sources {
+            let expression = Expression::parse(source).unwrap();
+            let inputs = expression.required_input_length();
+            let scalar = expression.clone().to_scalar_engine();
+            let compiled = expression.to_compiled_engine();
+            for &a in &values {
+                for &b in &values {
+                    for &c in &values {
+                        let row = &[a, b, c][..inputs];
+                        let expected = scalar.evaluate(row).unwrap();
+                        let result = compiled.evaluate(row).unwrap();
+                        assert!(
+                            result == expected || (result.is_nan() && expected.is_nan()),
+                            "{source} at {row:?}: {result} != {expected}"
+                        );
+                    }
+                }
+            }
+        }
+
+        let engine = Expression::parse("a * b").unwrap().to_compiled_engine();
+        assert_eq!(engine.evaluate_named(&[("b", 3.0), ("a", 2.0)]).unwrap(), 6.0);
+        assert!(matches!(
+            engine.evaluate(&[1.0]),
+            Err(engine::EvaluationError::InvalidInputLength(1, 2))
+        ));
+
+        let engine = Expression::parse("a - lag(a, 1)").unwrap().to_compiled_engine();
+        assert!(matches!(
+            engine.evaluate(&[1.0]),
+            Err(engine::EvaluationError::UnsupportedWindow(Function::Lag))
+        ));
+    }
+
+    #[test]
+    fn vectorized_conditionals() {
+        let engine = Expression::parse("if(a > b, a, 0)").unwrap().to_vectorized_engine();
+        let a = (0..10).map(|i| i as f64).collect::<Vec<_>>();
+        let b = (0..10).map(|i| 9.0 - i as f64).collect::<Vec<_>>();
+        let mut output = vec![0.0; a.len()];
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        let expected = a
+            .iter()
+            .zip(&b)
+            .map(|(&a, &b)| if a > b { a } else { 0.0 })
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+
+        let engine = Expression::parse("not (a > 2 and b) and a")
+            .unwrap()
+            .to_vectorized_engine();
+        let b = (0..10).map(|i| (i % 2) as f64).collect::<Vec<_>>();
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        let expected = a
+            .iter()
+            .zip(&b)
+            .map(|(&a, &b)| if !(a > 2.0 && b != 0.0) && a != 0.0 { 1.0 } else { 0.0 })
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn vectorized_intermediates() {
+        // Both operands of the outer operation are intermediate results that are live at the same time
+        let sources = [
+            "(a + b) * (c - d)",
+            "if(a > b, a - b, 0)",
+            "max(a * b, c * d) - min(a + c, b + d) / (a - d)",
+            "(a - b) ^ 2 + (c - d) ^ 2",
+            // Shared subexpressions that are computed once and read several times
+            "(a - b) * (a - b) + sqrt(abs(a - b)) - (a - b)",
+            "if(c > d, (a + b) * c, (a + b) / d) + (a + b) * c",
+        ];
+        let a = (0..11).map(|i| i as f64).collect::<Vec<_>>();
+        let b = (0..11).map(|i| 10.0 - i as f64).collect::<Vec<_>>();
+        let c = (0..11).map(|i| (i * i) as f64 * 0.5).collect::<Vec<_>>();
+        let d = (0..11).map(|i| -(i as f64) / 3.0 - 1.0).collect::<Vec<_>>();
+
+        for source in sources {
+            let expression = Expression::parse(source).unwrap();
+            let inputs = expression.required_input_length();
+            let scalar = expression.clone().to_scalar_engine();
+            let engine = expression.to_vectorized_engine();
+
+            let mut output = vec![0.0; a.len()];
+            assert!(engine.evaluate(&[&a[..], &b, &c, &d][..inputs], &mut output).is_ok());
+            for i in 0..a.len() {
+                let row = [a[i], b[i], c[i], d[i]];
+                let expected = scalar.evaluate(&row[..inputs]).unwrap();
+                assert_eq!(output[i], expected, "{source} at row {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn scalar_named_inputs() {
+        let expression = Expression::parse("a * a + b").unwrap();
+        assert_eq!(expression.required_input_length(), 2);
+
+        let engine = expression.to_scalar_engine();
+        let result = engine.evaluate_named(&[("b", 1.0), ("unused", 5.0), ("a", 3.0)]);
+        assert_eq!(result.unwrap(), 10.0);
+
+        let result = engine.evaluate_named(&[("a", 3.0)]);
+        assert!(matches!(result, Err(engine::EvaluationError::MissingInput(name)) if name == "b"));
+    }
+
+    #[test]
+    fn vectorized_named_inputs() {
+        let expression = Expression::parse("a * a + a").unwrap();
+        assert_eq!(expression.required_input_length(), 1);
+
+        let engine = expression.to_vectorized_engine();
+        let a = (0..7).map(|i| i as f64).collect::<Vec<_>>();
+        let mut output = vec![0.0; a.len()];
+        let result = engine.evaluate_named(&[("a", &a)], &mut output);
+        assert!(result.is_ok());
+        let expected = a.iter().map(|a| a * a + a).collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn vectorized_fused() {
+        // Several chunks plus a remainder that doesn't fill a vector
+        let count = 10_007;
+        let a = (0..count).map(|i| i as f64 * 0.25).collect::<Vec<_>>();
+        let b = (0..count).map(|i| (i % 13) as f64 - 6.0).collect::<Vec<_>>();
+
+        for source in ["(a - b) * (a + b) / (b * b + 1) + sqrt(abs(a * b - b))", "a", "2 * 3"] {
+            let expression = Expression::parse(source).unwrap();
+            let inputs = expression.required_input_length();
+            let engine = expression.to_vectorized_engine();
+            let input = &[&a[..], &b[..]][..inputs];
+
+            let mut expected = vec![0.0; count];
+            assert!(engine.evaluate(input, &mut expected).is_ok());
+            let mut output = vec![0.0; count];
+            assert!(engine.evaluate_fused(input, &mut output).is_ok());
+            assert_eq!(output, expected, "{source}");
+        }
+
+        let engine = Expression::parse("a + b").unwrap().to_vectorized_engine();
+        let mut output = vec![];
+        assert!(engine.evaluate_fused(&[&[], &[]], &mut output).is_ok());
+    }
+
+    #[test]
+    fn vectorized_parallel() {
+        let engine = Expression::parse("if(a > b, (a - b) ^ 2, sqrt(b - a)) + a * b")
+            .unwrap()
+            .to_vectorized_engine();
+
+        // The same pools for every call
+        let pools = [1, 2, 3, 8].map(|threads| ThreadPool::new(std::num::NonZeroUsize::new(threads).unwrap()));
+        for count in [0, 3, 1000, 100_003] {
+            let a = (0..count).map(|i| (i % 101) as f64).collect::<Vec<_>>();
+            let b = (0..count).map(|i| (i % 37) as f64 * 1.5).collect::<Vec<_>>();
+
+            let mut expected = vec![0.0; count];
+            assert!(engine.evaluate(&[&a, &b], &mut expected).is_ok());
+
+            for pool in &pools {
+                let mut output = vec![0.0; count];
+                assert!(engine.evaluate_parallel(&[&a, &b], &mut output, pool).is_ok());
+                assert_eq!(output, expected, "{count} rows on {} threads", pool.threads());
+            }
+        }
+
+        let mut output = vec![0.0; 3];
+        let result = engine.evaluate_parallel(&[&[1.0; 3], &[1.0; 2]], &mut output, &pools[0]);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::InvalidInputColumnLength(2, 3, 1))
+        ));
+    }
+
+    #[test]
+    fn typed_vectorized_engines() {
+        let source = "(a - b) * (a + b) / 2 + max(a, b) ^ 2";
+        let a = (0..37).map(|i| i as f64 - 10.0).collect::<Vec<_>>();
+        let b = (0..37).map(|i| (i % 5) as f64).collect::<Vec<_>>();
+        let mut expected = vec![0.0; a.len()];
+        let engine = Expression::parse(source).unwrap().to_vectorized_engine();
+        assert!(engine.evaluate(&[&a, &b], &mut expected).is_ok());
+
+        let engine = Expression::parse(source)
+            .unwrap()
+            .to_typed_vectorized_engine::<f64, 8>();
+        let mut output = vec![0.0; a.len()];
+        assert!(engine.unwrap().evaluate(&[&a, &b], &mut output).is_ok());
+        assert_eq!(output, expected);
+
+        // Every value involved is small enough to be exact in f32
+        let engine = Expression::parse(source)
+            .unwrap()
+            .to_typed_vectorized_engine::<f32, 16>();
+        let (a32, b32) = (
+            a.iter().map(|&v| v as f32).collect::<Vec<_>>(),
+            b.iter().map(|&v| v as f32).collect::<Vec<_>>(),
+        );
+        let mut output = vec![0.0f32; a.len()];
+        assert!(engine.unwrap().evaluate(&[&a32, &b32], &mut output).is_ok());
+        assert_eq!(output, expected.iter().map(|&v| v as f32).collect::<Vec<_>>());
+
+        // Integer division truncates
+        let engine = Expression::parse(source)
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>();
+        let (a64, b64) = (
+            a.iter().map(|&v| v as i64).collect::<Vec<_>>(),
+            b.iter().map(|&v| v as i64).collect::<Vec<_>>(),
+        );
+        let mut output = vec![0i64; a.len()];
+        assert!(engine.unwrap().evaluate(&[&a64, &b64], &mut output).is_ok());
+        let expected = a64
+            .iter()
+            .zip(&b64)
+            .map(|(&a, &b)| (a - b) * (a + b) / 2 + a.max(b).pow(2))
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn integer_type_checking() {
+        use engine::element::ElementType;
+
+        let check = |source: &str| Expression::parse(source).unwrap().check_type::<i64>();
+        assert!(check("a * 3 - b / 2 + a ^ 20").is_ok());
+        assert_eq!(
+            check("a * 0.5"),
+            Err(ExpressionConstructionError::UnrepresentableLiteral(ElementType::I64))
+        );
+        assert_eq!(
+            check("a + 1e30"),
+            Err(ExpressionConstructionError::UnrepresentableLiteral(ElementType::I64))
+        );
+        assert_eq!(
+            check("sqrt(a)"),
+            Err(ExpressionConstructionError::UnsupportedFunction {
+                function: Function::Sqrt,
+                element: ElementType::I64
+            })
+        );
+        assert_eq!(
+            check("a ^ b"),
+            Err(ExpressionConstructionError::InvalidExponent(ElementType::I64))
+        );
+        assert_eq!(
+            check("a ^ -1"),
+            Err(ExpressionConstructionError::InvalidExponent(ElementType::I64))
+        );
+
+        assert!(Expression::parse("sqrt(a) ^ b").unwrap().check_type::<f32>().is_ok());
+        assert!(Expression::parse("a * 1e30").unwrap().check_type::<f32>().is_ok());
+        assert_eq!(
+            Expression::parse("a + 1e300").unwrap().check_type::<f32>(),
+            Err(ExpressionConstructionError::UnrepresentableLiteral(ElementType::F32))
+        );
+        assert!(Expression::parse("a + 1e300").unwrap().check_type::<f64>().is_ok());
+    }
+
+    #[test]
+    fn integer_overflow() {
+        use engine::element::Overflow;
+
+        let a = [1, 2, 3, 4, 5, i64::MAX, 7];
+        let b = [1, 1, 1, 1, 1, 1, 0];
+        let mut output = [0; 7];
+
+        let engine = Expression::parse("a + b")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        let result = engine.evaluate(&[&a, &b], &mut output);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::Overflow { row: 5, operation: "+" })
+        ));
+
+        let engine = engine.with_overflow(Overflow::Wrapping);
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        assert_eq!(output[5], i64::MIN);
+
+        // Division by zero in the remainder that doesn't fill a vector
+        let engine = Expression::parse("a / b")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        let result = engine.evaluate_fused(&[&a, &b], &mut output);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::Overflow { row: 6, operation: "/" })
+        ));
+        let engine = engine.with_overflow(Overflow::Wrapping);
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        assert_eq!(output, [1, 2, 3, 4, 5, i64::MAX, 0]);
+
+        let engine = Expression::parse("abs(-a) * 2 ^ 62")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 2>()
+            .unwrap();
+        let result = engine.evaluate(&[&a], &mut output);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::Overflow { row: 1, operation: "*" })
+        ));
+    }
+
+    #[test]
+    fn null_propagation() {
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0];
+        let b = [1.0; 9];
+        // Rows 1 and 4 of `a` are null
+        let a_validity = [0b1110_1101, 0b1];
+        let mut output = [0.0; 9];
+        let mut output_validity = [0xff; 2];
+
+        let engine = Expression::parse("(a + b) * 2").unwrap().to_vectorized_engine();
+        let result = engine.evaluate_nullable(&[&a, &b], &[Some(&a_validity), None], &mut output, &mut output_validity);
+        assert!(result.is_ok());
+        assert_eq!(output_validity, a_validity);
+        assert_eq!(output[0], 4.0);
+        assert_eq!(output[8], 20.0);
+
+        let engine = Expression::parse("coalesce(a, 0) + is_null(a) * 100")
+            .unwrap()
+            .to_vectorized_engine();
+        let result = engine.evaluate_nullable(&[&a], &[Some(&a_validity)], &mut output, &mut output_validity);
+        assert!(result.is_ok());
+        assert_eq!(output, [1.0, 100.0, 3.0, 4.0, 100.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(output_validity, [0xff, 0b1]);
+
+        // A null condition selects the otherwise branch, whose validity is the result's
+        let engine = Expression::parse("if(a > 4, a, -b)").unwrap().to_vectorized_engine();
+        let b_validity = [0b1111_1110, 0b1];
+        let result = engine.evaluate_nullable(
+            &[&a, &b],
+            &[Some(&a_validity), Some(&b_validity)],
+            &mut output,
+            &mut output_validity,
+        );
+        assert!(result.is_ok());
+        assert_eq!(output[1..4], [-1.0, -1.0, -1.0]);
+        assert_eq!(output[4..], [-1.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(output_validity, [0b1111_1110, 0b1]);
+
+        // Without validity nothing is null
+        let engine = Expression::parse("coalesce(a, 0) + is_null(a)").unwrap();
+        assert_eq!(engine.to_scalar_engine().evaluate(&[2.0]).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn nullable_chunks() {
+        use engine::element::FloatPolicy;
+
+        // Enough rows for several chunks, where every third row is null
+        let a = (0..10_007).map(|j| j as f64).collect::<Vec<_>>();
+        let mut validity = vec![0u8; a.len().div_ceil(8)];
+        for j in (0..a.len()).filter(|j| j % 3 != 0) {
+            validity[j / 8] |= 1 << (j % 8);
+        }
+        let mut output = vec![0.0; a.len()];
+        let mut output_validity = vec![0; validity.len()];
+
+        let engine = Expression::parse("a * 2 + 1").unwrap().to_vectorized_engine();
+        let result = engine.evaluate_nullable(&[&a], &[Some(&validity)], &mut output, &mut output_validity);
+        assert!(result.is_ok());
+        assert_eq!(output_validity, validity);
+        assert!((0..a.len())
+            .filter(|j| j % 3 != 0)
+            .all(|j| output[j] == a[j] * 2.0 + 1.0));
+
+        // Row 9000 is null, row 9001 isn't
+        let engine = Expression::parse("1 / (a - 9000)")
+            .unwrap()
+            .to_vectorized_engine()
+            .with_float_policy(FloatPolicy::Error);
+        let result = engine.evaluate_nullable(&[&a], &[Some(&validity)], &mut output, &mut output_validity);
+        assert!(result.is_ok());
+        let engine = Expression::parse("1 / (a - 9001)")
+            .unwrap()
+            .to_vectorized_engine()
+            .with_float_policy(FloatPolicy::Error);
+        let result = engine.evaluate_nullable(&[&a], &[Some(&validity)], &mut output, &mut output_validity);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::NonFinite { row: 9001, .. })
+        ));
+
+        let result = engine.evaluate_nullable(&[&a], &[], &mut output, &mut output_validity);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::InvalidValidityCount(0, 1))
+        ));
+    }
+
+    #[test]
+    fn null_logic() {
+        // Every combination of true, false and null, where rows 6 to 8 of `a` and rows 2, 5 and 8 of `b` are null
+        let a = [1.0, 1.0, 1.0, -1.0, -1.0, -1.0, 0.0, 0.0, 0.0];
+        let b = [1.0, -1.0, 0.0, 1.0, -1.0, 0.0, 1.0, -1.0, 0.0];
+        let a_validity = [0b0011_1111, 0];
+        let b_validity = [0b1101_1011, 0];
+        let validity = [Some(&a_validity[..]), Some(&b_validity[..])];
+        let mut output = [0.0; 9];
+        let mut output_validity = [0; 2];
+
+        let engine = Expression::parse("a > 0 and b > 0").unwrap().to_vectorized_engine();
+        assert!(engine
+            .evaluate_nullable(&[&a, &b], &validity, &mut output, &mut output_validity)
+            .is_ok());
+        assert_eq!(output_validity, [0b1011_1011, 0]);
+        assert_eq!(
+            [output[0], output[1], output[3], output[4], output[5], output[7]],
+            [1.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+
+        let engine = Expression::parse("a > 0 or b > 0").unwrap().to_vectorized_engine();
+        assert!(engine
+            .evaluate_nullable(&[&a, &b], &validity, &mut output, &mut output_validity)
+            .is_ok());
+        assert_eq!(output_validity, [0b0101_1111, 0]);
+        assert_eq!(output[..5], [1.0, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(output[6], 1.0);
+
+        // Pipelines are kept for each combination of bitmaps
+        let only_a = [Some(&a_validity[..]), None];
+        for (validity, expected) in [(&only_a, [0b0111_1111, 0]), (&validity, [0b0101_1111, 0])] {
+            assert!(engine
+                .evaluate_nullable(&[&a, &b], validity, &mut output, &mut output_validity)
+                .is_ok());
+            assert_eq!(output_validity, expected);
+        }
+
+        let result = engine.evaluate_nullable(&[&a, &b], &validity, &mut output, &mut output_validity[..1]);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::InvalidOutputValidityLength(1, 2))
+        ));
+    }
+
+    #[test]
+    fn null_rows_dont_overflow() {
+        let a = [i64::MAX, 1, 2, 3, i64::MAX];
+        let b = [1; 5];
+        let mut output = [0; 5];
+        let mut output_validity = [0];
+
+        let engine = Expression::parse("a + b")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        let result = engine.evaluate_nullable(&[&a, &b], &[Some(&[0b0_1110]), None], &mut output, &mut output_validity);
+        assert!(result.is_ok());
+        assert_eq!(output_validity, [0b0_1110]);
+        assert_eq!(output[1..4], [2, 3, 4]);
+
+        let result = engine.evaluate_nullable(&[&a, &b], &[Some(&[0b1_1110]), None], &mut output, &mut output_validity);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::Overflow { row: 4, operation: "+" })
+        ));
+    }
+
+    #[test]
+    fn untaken_branches_dont_overflow() {
+        let a = [6, 5, 4, 3, 2, 1, i64::MAX];
+        let b = [1, 0, 2, 0, 3, 0, 1];
+        let mut output = [0; 7];
+        // `b` comes first in every expression, so it's always the first input
+        let engine = |source: &str| {
+            Expression::parse(source)
+                .unwrap()
+                .to_typed_vectorized_engine::<i64, 4>()
+                .unwrap()
+        };
+
+        let guarded = engine("if(b != 0, a / b, 0)");
+        assert!(guarded.evaluate(&[&b, &a], &mut output).is_ok());
+        assert_eq!(output, [6, 0, 2, 0, 0, 0, i64::MAX]);
+        assert!(guarded.evaluate_fused(&[&b, &a], &mut output).is_ok());
+
+        // Nested, and on the right side of `and` and `or`
+        for source in [
+            "if(b == 0, -1, if(a > 4, a / b, a * 2 / b))",
+            "b != 0 and a / b > 1",
+            "b == 0 or a / b > 1",
+        ] {
+            assert!(engine(source).evaluate(&[&b, &a], &mut output).is_ok(), "{source}");
+        }
+
+        // Still checked where the branch is taken, or where the same value is used outside of it
+        for source in [
+            "if(b < 9, a / b, 0)",
+            "if(b != 0, a / b, 0) + a / b",
+            "if(b != 0, a + a, 0)",
+        ] {
+            assert!(matches!(
+                engine(source).evaluate(&[&b, &a], &mut output),
+                Err(engine::EvaluationError::Overflow { row: 1 | 6, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn reductions() {
+        use engine::reduction::Reduction;
+
+        // Enough rows for several chunks, and a remainder that doesn't fill a vector
+        let a = (0..10_007).map(|j| j as f64).collect::<Vec<_>>();
+        let b = (0..10_007).map(|j| (j % 3) as f64).collect::<Vec<_>>();
+        let engine = Expression::parse("a * 2 + b").unwrap().to_vectorized_engine();
+
+        let mut values = vec![0.0; a.len()];
+        engine.evaluate(&[&a, &b], &mut values).unwrap();
+        let sum = values.iter().sum::<f64>();
+        let mean = sum / values.len() as f64;
+        let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+
+        let reduce = |reduction| engine.reduce(&[&a, &b], reduction).unwrap().unwrap();
+        assert_eq!(reduce(Reduction::Sum), sum);
+        assert_eq!(reduce(Reduction::Mean), mean);
+        assert_eq!(reduce(Reduction::Min), 0.0);
+        assert_eq!(reduce(Reduction::Max), 20_012.0 + 1.0);
+        assert_eq!(reduce(Reduction::Count), 10_007.0);
+        assert!((reduce(Reduction::Variance) - variance).abs() < variance * 1e-12);
+
+        let engine = Expression::parse("a - b")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        let result = engine.reduce(&[&[1, 2, 3], &[3, 2, 1]], Reduction::Max);
+        assert_eq!(result.unwrap(), Some(2.0));
+        assert_eq!(engine.reduce(&[&[], &[]], Reduction::Sum).unwrap(), None);
+
+        // Sums of integers are exact, and overflow like the operations of the engine
+        let engine = Expression::parse("a")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        assert_eq!(
+            engine.reduce(&[&[i64::MAX, 1, -i64::MAX]], Reduction::Sum).unwrap(),
+            Some(1.0)
+        );
+        let result = engine.reduce(&[&[i64::MAX, 1]], Reduction::Sum);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::Overflow {
+                row: 1,
+                operation: "sum"
+            })
+        ));
+        let engine = engine.with_overflow(engine::element::Overflow::Wrapping);
+        let result = engine.reduce(&[&[i64::MAX, 1]], Reduction::Sum);
+        assert_eq!(result.unwrap(), Some(i64::MIN as f64));
+    }
+
+    #[test]
+    fn grouped_reductions() {
+        use engine::reduction::Reduction;
+
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        let keys = [3, 1, 3, 1, 3, 2, 1];
+        let engine = Expression::parse("a * 10").unwrap().to_vectorized_engine();
+
+        let sums = engine.reduce_grouped(&[&a], &keys, Reduction::Sum).unwrap();
+        assert_eq!(
+            sums.into_iter().collect::<Vec<_>>(),
+            [(1, Some(130.0)), (2, Some(60.0)), (3, Some(90.0))]
+        );
+
+        let variances = engine.reduce_grouped(&[&a], &keys, Reduction::Variance).unwrap();
+        assert!((variances[&1].unwrap() - 1900.0 / 3.0).abs() < 1e-9);
+        assert_eq!(variances[&2], None);
+
+        // Group 3 only has NaN rows
+        let engine = Expression::parse("sqrt(a - 5.5)").unwrap().to_vectorized_engine();
+        let minimums = engine.reduce_grouped(&[&a], &keys, Reduction::Min).unwrap();
+        assert_eq!(minimums[&1], Some(1.5f64.sqrt()));
+        assert!(minimums[&3].unwrap().is_nan());
+
+        let result = engine.reduce_grouped(&[&a], &keys[1..], Reduction::Count);
+        assert!(matches!(result, Err(engine::EvaluationError::InvalidKeyLength(6, 7))));
+    }
+
+    #[test]
+    fn expression_sets() {
+        let sources = ["a + b", "(a + b) * c", "c - d", "c", "2 + 3"];
+        let set = ExpressionSet::new(sources.map(|source| Expression::parse(source).unwrap()));
+        assert_eq!(set.variables(), ["a", "b", "c", "d"]);
+
+        let columns = (0..4)
+            .map(|i| (0..10_007).map(|j| (j * (i + 1)) as f64).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let input = columns.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+        let engine = set.to_vectorized_engine();
+        let mut results = vec![vec![0.0; 10_007]; sources.len()];
+        let mut outputs = results.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>();
+        assert!(engine.evaluate(&input, &mut outputs).is_ok());
+        let mut parallel_results = vec![vec![0.0; 10_007]; sources.len()];
+        let mut outputs = parallel_results.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>();
+        assert!(engine
+            .evaluate_parallel(
+                &input,
+                &mut outputs,
+                &ThreadPool::new(std::num::NonZeroUsize::new(3).unwrap())
+            )
+            .is_ok());
+        assert_eq!(parallel_results, results);
+
+        for (source, result) in sources.iter().zip(&results) {
+            let expression = Expression::parse(source).unwrap();
+            let engine = expression.to_vectorized_engine();
+            let mut expected = vec![0.0; 10_007];
+            assert!(engine
+                .evaluate_named(
+                    &[("a", input[0]), ("b", input[1]), ("c", input[2]), ("d", input[3])],
+                    &mut expected
+                )
+                .is_ok());
+            assert_eq!(result, &expected, "{source}");
+        }
+
+        let mut outputs = results.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>();
+        let result = engine.evaluate(&input, &mut outputs[1..]);
+        assert!(matches!(result, Err(engine::EvaluationError::InvalidOutputCount(4, 5))));
+    }
+
+    #[test]
+    fn windows() {
+        use engine::reduction::Reduction;
+
+        // Enough rows that windows cross chunks and partitions
+        let x = (0..10_007).map(|j| ((j * 7) % 13) as f64).collect::<Vec<_>>();
+        let engine = Expression::parse("x - lag(x) + rolling_mean(x, 20) * lead(x, 3)")
+            .unwrap()
+            .to_vectorized_engine();
+        let expected = (0..x.len())
+            .map(|j| {
+                let lag = j.checked_sub(1).map_or(f64::NAN, |i| x[i]);
+                let mean = j
+                    .checked_sub(19)
+                    .map_or(f64::NAN, |i| x[i..=j].iter().sum::<f64>() / 20.0);
+                x[j] - lag + mean * x.get(j + 3).copied().unwrap_or(f64::NAN)
+            })
+            .collect::<Vec<_>>();
+
+        // Null rows are NaN, which only equals itself bit for bit
+        let bits = |values: &[f64]| values.iter().map(|value| value.to_bits()).collect::<Vec<_>>();
+
+        let mut output = vec![0.0; x.len()];
+        assert!(engine.evaluate(&[&x], &mut output).is_ok());
+        assert_eq!(bits(&output), bits(&expected));
+        let mut fused = vec![0.0; x.len()];
+        assert!(engine.evaluate_fused(&[&x], &mut fused).is_ok());
+        assert_eq!(bits(&fused), bits(&expected));
+        let mut parallel = vec![0.0; x.len()];
+        assert!(engine
+            .evaluate_parallel(
+                &[&x],
+                &mut parallel,
+                &ThreadPool::new(std::num::NonZeroUsize::new(3).unwrap())
+            )
+            .is_ok());
+        assert_eq!(bits(&parallel), bits(&expected));
+
+        // Null rows at the edges are skipped
+        let count = engine.reduce(&[&x], Reduction::Count).unwrap();
+        assert_eq!(count, Some((x.len() - 19 - 3) as f64));
+
+        // Edge rows are null, so a fill value goes through `coalesce`
+        let engine = Expression::parse("coalesce(lag(lag(x), 2), -1)")
+            .unwrap()
+            .to_vectorized_engine();
+        let mut output = [0.0; 5];
+        let mut output_validity = [0];
+        let x = [1.0, 2.0, 3.0, 4.0, 5.0];
+        let x_validity = [0b1_1110];
+        assert!(engine
+            .evaluate_nullable(&[&x], &[Some(&x_validity)], &mut output, &mut output_validity)
+            .is_ok());
+        assert_eq!(output, [-1.0, -1.0, -1.0, -1.0, 2.0]);
+        assert_eq!(output_validity, [0b1_1111]);
+
+        let engine = Expression::parse("rolling_sum(x, 2)").unwrap().to_vectorized_engine();
+        assert!(engine
+            .evaluate_nullable(&[&x], &[Some(&x_validity)], &mut output, &mut output_validity)
+            .is_ok());
+        assert_eq!(output[2..], [5.0, 7.0, 9.0]);
+        assert_eq!(output_validity, [0b1_1100]);
+
+        // Integers have no NaN, so null rows are 0
+        let engine = Expression::parse("lag(x)")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        let mut output = [-1; 5];
+        assert!(engine.evaluate(&[&[1, 2, 3, 4, 5]], &mut output).is_ok());
+        assert_eq!(output, [0, 1, 2, 3, 4]);
+
+        let engine = Expression::parse("rolling_sum(x, 2)")
+            .unwrap()
+            .to_typed_vectorized_engine::<i64, 4>()
+            .unwrap();
+        let mut output = [0; 3];
+        assert!(matches!(
+            engine.evaluate(&[&[1, i64::MAX, 1]], &mut output),
+            Err(engine::EvaluationError::Overflow {
+                row: 1,
+                operation: "rolling_sum"
+            })
+        ));
+    }
+
+    #[test]
+    fn window_sizes() {
+        assert_eq!(
+            Expression::parse("lag(x)").unwrap().nodes,
+            Expression::parse("lag(x, 1)").unwrap().nodes
+        );
+
+        for source in ["lag(x, 1.5)", "lead(x, y)", "rolling_mean(x, 0)", "lag(x, -1)"] {
+            let error = Expression::parse(source).unwrap_err().error;
+            assert!(
+                matches!(error, ExpressionConstructionError::InvalidWindowSize(_)),
+                "{source}: {error}"
+            );
+        }
+
+        let engine = Expression::parse("x - lag(x)").unwrap().to_scalar_engine();
+        assert!(matches!(
+            engine.evaluate(&[1.0]),
+            Err(engine::EvaluationError::UnsupportedWindow(Function::Lag))
+        ));
+    }
+
+    #[test]
+    fn builder() {
+        let cases = [
+            (col("a") + col("b") * lit(2.0), "a + b * 2"),
+            ((col("a") + col("b")) * 2.0, "(a + b) * 2"),
+            (1.0 - -col("x").sqrt() / col("y").pow(2.0), "1 - -sqrt(x) / y ^ 2"),
+            (
+                when(col("a").gt(0.0) & !col("b").is_null())
+                    .then(col("a").clamp(0.0, 1.0))
+                    .otherwise(col("b").coalesce(0.0).max(col("c"))),
+                "if(a > 0 and !is_null(b), clamp(a, 0, 1), max(coalesce(b, 0), c))",
+            ),
+            (
+                (col("x") - col("x").lag(1)) | col("x").rolling_mean(20).le(3.0),
+                "x - lag(x) or rolling_mean(x, 20) <= 3",
+            ),
+        ];
+        for (formula, source) in cases {
+            let expression = formula.build().unwrap();
+            assert_eq!(expression.nodes, Expression::parse(source).unwrap().nodes, "{source}");
+        }
+
+        let expression = (col("b") * col("a") + col("b")).build().unwrap();
+        assert_eq!(expression.variables(), ["b", "a"]);
+        let result = expression.to_scalar_engine().evaluate(&[2.0, 3.0]);
+        assert_eq!(result.unwrap(), 8.0);
+
+        let result = Expression::try_from(col("x").rolling_sum(0));
+        assert_eq!(result.unwrap_err(), ExpressionConstructionError::InvalidWindowSize(1));
+    }
+
+    #[test]
+    fn display() {
+        let cases = [
+            ("a + b * c", "a + b * c"),
+            ("(a + b) * c", "(a + b) * c"),
+            ("a - (b - c) - d", "a - (b - c) - d"),
+            ("((a / b)) / c", "a / b / c"),
+            ("a ^ b ^ c", "a ^ b ^ c"),
+            ("(a ^ b) ^ c", "(a ^ b) ^ c"),
+            ("-a ^ 2", "-a ^ 2"),
+            ("(-a) ^ 2", "(-a) ^ 2"),
+            ("a * -(b + c)", "a * -(b + c)"),
+            ("- -a", "--a"),
+            ("!a || b && !(c > 1)", "not a or b and not c > 1"),
+            ("(not a) > b", "(not a) > b"),
+            ("a * (not b) + c", "a * (not b) + c"),
+            ("not (a and b)", "not (a and b)"),
+            ("(a or b) and c", "(a or b) and c"),
+            ("a < b == (c < d)", "a < b == c < d"),
+            ("a == (b == c)", "a == (b == c)"),
+            (
+                "if(a, max(b, c), clamp(-a, 0, 1)) + lag(x)",
+                "if(a, max(b, c), clamp(-a, 0, 1)) + lag(x, 1)",
+            ),
+            (
+                "0.1 + 2.5e-10 * 1e300 - 1000000",
+                "0.1 + 0.00000000025 * 1e300 - 1000000",
+            ),
+        ];
+        for (source, expected) in cases {
+            let expression = Expression::parse(source).unwrap();
+            let printed = expression.to_string();
+            assert_eq!(printed, expected);
+            assert_eq!(Expression::parse(&printed).unwrap().nodes, expression.nodes, "{source}");
+        }
+
+        // Literals that only come from other builders print as what computes them
+        let expression = ((lit(-2.0).pow(col("x")) + lit(f64::NAN)) * lit(f64::NEG_INFINITY))
+            .build()
+            .unwrap();
+        let printed = expression.to_string();
+        assert_eq!(printed, "((-2) ^ x + 0 / 0) * (-1 / 0)");
+        let reparsed = Expression::parse(&printed).unwrap();
+        assert_eq!(reparsed.to_string(), printed);
+        let evaluate = |expression: Expression| expression.optimize().to_scalar_engine().evaluate(&[3.0]).unwrap();
+        assert!(evaluate(reparsed).is_nan());
+        assert_eq!(evaluate(Expression::parse("(-2) ^ x").unwrap()), -8.0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let expression = Expression::parse("(a + b) * -c").unwrap();
+        let json = serde_json::to_string(&expression).unwrap();
+        assert_eq!(json, r#""(a + b) * -c""#);
+        let deserialized: Expression = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.nodes, expression.nodes);
+        assert!(serde_json::from_str::<Expression>(r#""a +""#).is_err());
+
+        // Variables that the formula doesn't have any more are kept, in the same order
+        let derivative = Expression::parse("a * a + 3 * b").unwrap().derivative("b");
+        let json = serde_json::to_string(&derivative).unwrap();
+        assert_eq!(json, r#"{"formula":"3","variables":["a","b"]}"#);
+        let deserialized: Expression = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.required_input_length(), 2);
+        let set = ExpressionSet::new([Expression::parse("b").unwrap(), Expression::parse("a + b").unwrap()]);
+        let json = serde_json::to_string(&set.expressions()[1]).unwrap();
+        assert_eq!(json, r#"{"formula":"a + b","variables":["b","a"]}"#);
+        let deserialized: Expression = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.variables(), ["b", "a"]);
+        assert_eq!(deserialized.operands, set.expressions()[1].operands);
+
+        for json in [
+            r#"{"formula":"a + b","variables":["a"]}"#,
+            r#"{"formula":"a","variables":["a","a"]}"#,
+            r#"{"formula":"a"}"#,
+        ] {
+            assert!(serde_json::from_str::<Expression>(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn float_policy() {
+        use engine::element::FloatPolicy;
+
+        // Rows 5 and 6 create infinity and NaN, row 1 only passes on the NaN it was given
+        let a = [1.0, f64::NAN, 3.0, 4.0, 5.0, 6.0, 0.0];
+        let b = [1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0];
+        let mut output = [0.0; 7];
+
+        let engine = Expression::parse("a / b + 1").unwrap().to_vectorized_engine();
+        assert!(engine.evaluate(&[&a, &b], &mut output).is_ok());
+        assert_eq!(output[5], f64::INFINITY);
+
+        let engine = engine.with_float_policy(FloatPolicy::Error);
+        let result = engine.evaluate(&[&a, &b], &mut output);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::NonFinite { row: 5, operation: "/" })
+        ));
+
+        let engine = engine.with_float_policy(FloatPolicy::Replace(-1.0));
+        assert!(engine.evaluate_fused(&[&a, &b], &mut output).is_ok());
+        assert_eq!(output[2..], [4.0, 5.0, 6.0, 0.0, 0.0]);
+        assert!(output[1].is_nan());
+
+        // Null rows are never reported
+        let engine = Expression::parse("sqrt(a - 2)")
+            .unwrap()
+            .to_typed_vectorized_engine::<f32, 8>()
+            .unwrap()
+            .with_float_policy(FloatPolicy::Error);
+        let a = (0..20).map(|j| j as f32).collect::<Vec<_>>();
+        let mut output = vec![0.0; 20];
+        let mut output_validity = [0; 3];
+        let result = engine.evaluate(&[&a], &mut output);
+        assert!(matches!(
+            result,
+            Err(engine::EvaluationError::NonFinite {
+                row: 0,
+                operation: "sqrt"
+            })
+        ));
+        let result = engine.evaluate_nullable(
+            &[&a],
+            &[Some(&[0b1111_1100, 0xff, 0xff])],
+            &mut output,
+            &mut output_validity,
+        );
+        assert!(result.is_ok());
+
+        let engine = Expression::parse("ln(a) * b").unwrap().to_scalar_engine();
+        assert_eq!(engine.evaluate(&[0.0, 2.0]).unwrap(), f64::NEG_INFINITY);
+        let engine = engine.with_float_policy(FloatPolicy::Error);
+        assert!(matches!(
+            engine.evaluate(&[0.0, 2.0]),
+            Err(engine::EvaluationError::NonFinite {
+                row: 0,
+                operation: "ln"
+            })
+        ));
+        assert!(engine.evaluate(&[f64::NAN, 2.0]).unwrap().is_nan());
+        let engine = engine.with_float_policy(FloatPolicy::Replace(0.0));
+        assert_eq!(engine.evaluate(&[0.0, 2.0]).unwrap(), 0.0);
+
+        // Operations on literals only are checked as well, in the first row where they're used
+        let a = [1.0, 2.0, 3.0, 4.0];
+        let cases = [
+            ("ln(0) * a", 0, "ln"),
+            ("a + 1 / 0", 0, "/"),
+            ("if(a > 2, 1 / 0, a)", 2, "/"),
+        ];
+        for (source, row, operation) in cases {
+            let expression = Expression::parse(source).unwrap();
+            let scalar = expression
+                .clone()
+                .to_scalar_engine()
+                .with_float_policy(FloatPolicy::Error);
+            assert_eq!(
+                a.iter().position(|&a| scalar.evaluate(&[a]).is_err()),
+                Some(row),
+                "{source}"
+            );
+
+            let engine = expression
+                .optimize()
+                .to_vectorized_engine()
+                .with_float_policy(FloatPolicy::Error);
+            let result = engine.evaluate(&[&a], &mut [0.0; 4]);
+            let expected = engine::EvaluationError::NonFinite { row, operation };
+            assert_eq!(result.unwrap_err().to_string(), expected.to_string(), "{source}");
+        }
+    }
+
+    #[test]
+    fn untaken_branches_are_not_checked() {
+        use engine::element::FloatPolicy;
+
+        let b = [1.0, 0.0, 2.0, 0.0, 4.0, 0.0, 8.0];
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        let mut output = [0.0; 7];
+
+        for source in [
+            "if(b != 0, a / b, 0)",
+            "b != 0 and ln(a / b) < 1",
+            "b == 0 or ln(b) > a",
+        ] {
+            let expression = Expression::parse(source).unwrap();
+            let scalar = expression
+                .clone()
+                .to_scalar_engine()
+                .with_float_policy(FloatPolicy::Error);
+            let engine = expression.to_vectorized_engine().with_float_policy(FloatPolicy::Error);
+
+            assert!(engine.evaluate(&[&b, &a], &mut output).is_ok(), "{source}");
+            for j in 0..a.len() {
+                assert_eq!(
+                    scalar.evaluate(&[b[j], a[j]]).unwrap(),
+                    output[j],
+                    "{source} at row {j}"
+                );
+            }
+            assert!(engine.evaluate_fused(&[&b, &a], &mut output).is_ok(), "{source}");
+        }
+
+        // The taken branch is still checked
+        let engine = Expression::parse("if(b < 9, a / b, 0)")
+            .unwrap()
+            .to_vectorized_engine()
+            .with_float_policy(FloatPolicy::Error);
+        assert!(matches!(
+            engine.evaluate(&[&b, &a], &mut output),
+            Err(engine::EvaluationError::NonFinite { row: 1, operation: "/" })
+        ));
+    }
 }
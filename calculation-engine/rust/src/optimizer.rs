@@ -0,0 +1,298 @@
+use crate::engine::plan::{self, Op};
+use crate::{Expression, Function, Node, Operator, UnaryOperator};
+
+/// What to replace a node and its operands with
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Rewrite {
+    Keep,
+    Literal(f64),
+    /// Only the operand at this position is left, e.g. `x` for `x * 1`
+    Operand(usize),
+    /// Division by the literal operand becomes multiplication by its reciprocal
+    Reciprocal,
+    /// `--x`, where both negations cancel out
+    Unnegate,
+}
+
+/// Rewrites the postfix form of an expression, in a single pass, into fewer operations that evaluate the same.
+pub(crate) fn optimize(expression: Expression) -> Expression {
+    let Expression {
+        nodes,
+        variables,
+        max_stack_depth,
+        ..
+    } = expression;
+
+    let mut result: Vec<Node> = Vec::with_capacity(nodes.len());
+    // Start of every operand on the stack, as an index into `result`
+    let mut starts: Vec<usize> = Vec::with_capacity(max_stack_depth);
+
+    for mut node in nodes {
+        let first = starts.len() - node.arity();
+        let start = starts.get(first).copied().unwrap_or(result.len());
+
+        loop {
+            let mut operands: [&[Node]; 3] = [&[]; 3];
+            for (k, operand) in operands.iter_mut().enumerate().take(node.arity()) {
+                let end = starts.get(first + k + 1).copied().unwrap_or(result.len());
+                *operand = &result[starts[first + k]..end];
+            }
+
+            match simplify(&node, &operands[..node.arity()]) {
+                Rewrite::Keep => result.push(node),
+                Rewrite::Literal(value) => {
+                    result.truncate(start);
+                    result.push(Node::Literal(value));
+                }
+                Rewrite::Operand(k) => {
+                    let end = starts.get(first + k + 1).copied().unwrap_or(result.len());
+                    result.truncate(end);
+                    result.drain(start..starts[first + k]);
+                }
+                Rewrite::Reciprocal => {
+                    let divisor = result.last_mut().unwrap();
+                    if let Node::Literal(value) = divisor {
+                        *divisor = Node::Literal(1.0 / *value);
+                    }
+                    // `x / 1` has become `x * 1`, which may simplify further
+                    node = Node::Operator(Operator::Mul);
+                    continue;
+                }
+                Rewrite::Unnegate => {
+                    result.pop();
+                }
+            }
+            break;
+        }
+
+        starts.truncate(first);
+        starts.push(start);
+    }
+
+    // Variables that were optimized away keep their position, so the input columns don't change
+    let mut variables = variables;
+    let operands = Expression::operands(&result, &mut variables);
+    let max_stack_depth = Expression::max_stack_depth(&result).expect("rewrites keep the postfix form balanced");
+
+    Expression {
+        nodes: result,
+        variables,
+        operands,
+        max_stack_depth,
+    }
+}
+
+fn simplify(node: &Node, operands: &[&[Node]]) -> Rewrite {
+    let Some(op) = Op::from_node(node) else {
+        return Rewrite::Keep;
+    };
+    // Even literals have null rows at the edges of a window
+    if matches!(node, Node::Function(function) if function.is_window()) {
+        return Rewrite::Keep;
+    }
+
+    let literals = operands
+        .iter()
+        .map_while(|operand| literal(operand))
+        .collect::<Vec<_>>();
+    // Results such as `1 / 0` are left to evaluation, which reports them under `FloatPolicy::Error`
+    if literals.len() == operands.len() {
+        return plan::fold(op, &literals).map_or(Rewrite::Keep, Rewrite::Literal);
+    }
+
+    let constant = |k: usize| literal(operands[k]);
+    match node {
+        Node::Unary(UnaryOperator::Plus) => Rewrite::Operand(0),
+        Node::Unary(UnaryOperator::Neg) if operands[0].last() == Some(&Node::Unary(UnaryOperator::Neg)) => {
+            Rewrite::Unnegate
+        }
+        // `-0 + 0` is 0, so only adding -0 or subtracting 0 keeps the sign of every `x`
+        Node::Operator(Operator::Add) if is_zero(constant(1), -0.0) => Rewrite::Operand(0),
+        Node::Operator(Operator::Add) if is_zero(constant(0), -0.0) => Rewrite::Operand(1),
+        Node::Operator(Operator::Sub) if is_zero(constant(1), 0.0) => Rewrite::Operand(0),
+        Node::Operator(Operator::Mul) if constant(1) == Some(1.0) => Rewrite::Operand(0),
+        Node::Operator(Operator::Mul) if constant(0) == Some(1.0) => Rewrite::Operand(1),
+        // `x * 0` is NaN for infinite or NaN `x`, so this only holds when `x` is known to be 0 or 1.
+        // Operands that are dropped must not be null either, since a null operand makes the result null
+        Node::Operator(Operator::Mul)
+            if (is_zero(constant(1), 0.0) && is_boolean(operands[0]) && is_never_null(operands[0]))
+                || (is_zero(constant(0), 0.0) && is_boolean(operands[1]) && is_never_null(operands[1])) =>
+        {
+            Rewrite::Literal(0.0)
+        }
+        // Exact for powers of two, otherwise the result may differ from the division in the last bit
+        Node::Operator(Operator::Div) if matches!(constant(1), Some(divisor) if (1.0 / divisor).is_normal()) => {
+            Rewrite::Reciprocal
+        }
+        Node::Operator(Operator::Pow) if constant(1) == Some(1.0) => Rewrite::Operand(0),
+        // Holds for NaN as well, `powf(NaN, 0)` is 1
+        Node::Operator(Operator::Pow) if constant(1) == Some(0.0) && is_never_null(operands[0]) => {
+            Rewrite::Literal(1.0)
+        }
+        Node::Operator(Operator::And) if constant(0) == Some(0.0) => Rewrite::Literal(0.0),
+        Node::Operator(Operator::Or) if matches!(constant(0), Some(value) if value != 0.0) => Rewrite::Literal(1.0),
+        Node::Function(Function::If) => match constant(0) {
+            Some(condition) if condition != 0.0 => Rewrite::Operand(1),
+            Some(_) => Rewrite::Operand(2),
+            None => Rewrite::Keep,
+        },
+        // Literals are never null, so neither is the result, whether the fallback is null or not
+        Node::Function(Function::Coalesce) if constant(0).is_some() => Rewrite::Operand(0),
+        _ => Rewrite::Keep,
+    }
+}
+
+fn literal(operand: &[Node]) -> Option<f64> {
+    match operand {
+        [Node::Literal(value)] => Some(*value),
+        _ => None,
+    }
+}
+
+/// Whether the literal is the zero of the same sign as `zero`
+fn is_zero(literal: Option<f64>, zero: f64) -> bool {
+    literal.is_some_and(|value| value.to_bits() == zero.to_bits())
+}
+
+/// Whether the operand is valid in every row, see `vectorized::Engine::evaluate_nullable`
+fn is_never_null(operand: &[Node]) -> bool {
+    let nullable = |node: &Node| match node {
+        Node::Variable(_) => true,
+        Node::Function(function) => function.is_window(),
+        _ => false,
+    };
+    matches!(operand.last(), Some(Node::Function(Function::IsNull))) || !operand.iter().any(nullable)
+}
+
+/// Whether the operand always evaluates to either 0 or 1
+fn is_boolean(operand: &[Node]) -> bool {
+    matches!(
+        operand.last(),
+        Some(
+            Node::Unary(UnaryOperator::Not)
+                | Node::Function(Function::IsNull)
+                | Node::Operator(
+                    Operator::Eq
+                        | Operator::Ne
+                        | Operator::Lt
+                        | Operator::Le
+                        | Operator::Gt
+                        | Operator::Ge
+                        | Operator::And
+                        | Operator::Or
+                )
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized(source: &str) -> Vec<Node> {
+        Expression::parse(source).unwrap().optimize().nodes
+    }
+
+    fn postfix(source: &str) -> Vec<Node> {
+        Expression::parse(source).unwrap().nodes
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(optimized("2 * 3 + 4"), [Node::Literal(10.0)]);
+        assert_eq!(optimized("a * (2 ^ 3 - max(1, 2))"), postfix("a * 6"));
+        assert_eq!(optimized("if(1 > 2, a, b - 0)"), postfix("b"));
+    }
+
+    #[test]
+    fn non_finite_constants() {
+        assert_eq!(optimized("a + 1 / 0"), postfix("a + 1 / 0"));
+        assert_eq!(optimized("ln(0) * a"), postfix("ln(0) * a"));
+        assert_eq!(optimized("a + 1 / 0 * 2"), postfix("a + 1 / 0 * 2"));
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(optimized("(a + -0) * 1 - 0"), postfix("a"));
+        assert_eq!(optimized("1 * (-0 + a) ^ 1"), postfix("a"));
+        assert_eq!(optimized("--a + +b"), postfix("a + b"));
+        assert_eq!(optimized("is_null(a) ^ 0"), [Node::Literal(1.0)]);
+        assert_eq!(optimized("a / 1"), postfix("a"));
+        assert_eq!(optimized("0 and a"), [Node::Literal(0.0)]);
+        assert_eq!(optimized("2 or a"), [Node::Literal(1.0)]);
+        assert_eq!(optimized("coalesce(3, a)"), [Node::Literal(3.0)]);
+    }
+
+    #[test]
+    fn multiplication_by_zero() {
+        assert_eq!(optimized("c - is_null(a > b) * 0"), postfix("c"));
+        // `a` could be infinite or NaN
+        assert_eq!(optimized("a * 0"), postfix("a * 0"));
+    }
+
+    #[test]
+    fn negative_zero() {
+        // `-0 + 0` is 0, and `-0 - -0` is 0 as well
+        assert_eq!(optimized("a + 0"), postfix("a + 0"));
+        assert_eq!(optimized("0 + a"), postfix("0 + a"));
+        let a = Node::Variable("a".into());
+        assert_eq!(
+            optimized("a - -0"),
+            [a, Node::Literal(-0.0), Node::Operator(Operator::Sub)]
+        );
+        assert_eq!(optimized("(a > b) * -0").last(), Some(&Node::Operator(Operator::Mul)));
+
+        let expression = Expression::parse("1 / (a + 0)").unwrap();
+        let optimized = expression.clone().optimize().to_scalar_engine();
+        assert_eq!(expression.to_scalar_engine().evaluate(&[-0.0]).unwrap(), f64::INFINITY);
+        assert_eq!(optimized.evaluate(&[-0.0]).unwrap(), f64::INFINITY);
+    }
+
+    #[test]
+    fn division_by_constant() {
+        assert_eq!(optimized("a / 4"), postfix("a * 0.25"));
+        assert_eq!(optimized("a / (1 + 1) / 2"), postfix("a * 0.5 * 0.5"));
+        // The reciprocal would be infinite
+        assert_eq!(optimized("a / 0"), postfix("a / 0"));
+        assert_eq!(optimized("a / 1e-310"), postfix("a / 1e-310"));
+    }
+
+    #[test]
+    fn nulls() {
+        // A null `a` makes the result null, so it can't be dropped
+        assert_eq!(optimized("a ^ 0"), postfix("a ^ 0"));
+        assert_eq!(optimized("c - (a > b) * 0"), postfix("c - (a > b) * 0"));
+
+        for source in [
+            "a ^ 0",
+            "(a > 1) * 0",
+            "coalesce(3, a)",
+            "0 and a",
+            "2 or a",
+            "if(1, a, 2)",
+        ] {
+            let expression = Expression::parse(source).unwrap();
+            let mut validity = Vec::new();
+            for engine in [expression.clone(), expression.clone().optimize()].map(|e| e.to_vectorized_engine()) {
+                let mut output = [0.0; 2];
+                let mut output_validity = [0];
+                engine
+                    .evaluate_nullable(&[&[2.0, 2.0]], &[Some(&[0b01])], &mut output, &mut output_validity)
+                    .unwrap();
+                validity.push(output_validity[0]);
+            }
+            assert_eq!(validity[0], validity[1], "{source}");
+        }
+    }
+
+    #[test]
+    fn variables_are_kept() {
+        let expression = Expression::parse("if(is_null(b > a) * 0, b, c)").unwrap().optimize();
+        assert_eq!(expression.variables(), ["b", "a", "c"]);
+        assert_eq!(expression.nodes, [Node::Variable("c".into())]);
+        assert_eq!(expression.operands, [2]);
+
+        let result = expression.to_scalar_engine().evaluate(&[1.0, 2.0, 3.0]);
+        assert_eq!(result.unwrap(), 3.0);
+    }
+}
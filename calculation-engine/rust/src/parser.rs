@@ -0,0 +1,384 @@
+use std::ops::Range;
+
+use crate::{Expression, ExpressionConstructionError, Function, Node, Operator, ParseError, UnaryOperator};
+
+pub(crate) fn parse(source: &str) -> Result<Expression, ParseError> {
+    let tokens = tokenize(source)?;
+    let (nodes, spans): (Vec<Node>, Vec<Range<usize>>) = tokens.into_iter().unzip();
+
+    Expression::from_infix_at(&nodes).map_err(|(error, i)| {
+        let span = match spans.get(i) {
+            Some(span) => span.clone(),
+            None if nodes.is_empty() => 0..source.len(),
+            None => source.len()..source.len(),
+        };
+        ParseError { error, span }
+    })
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Node, Range<usize>)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let node = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Node::LeftParens,
+            ')' => Node::RightParens,
+            ',' => Node::Separator,
+            '+' => Node::Operator(Operator::Add),
+            '-' => Node::Operator(Operator::Sub),
+            '*' => Node::Operator(Operator::Mul),
+            '/' => Node::Operator(Operator::Div),
+            '^' => Node::Operator(Operator::Pow),
+            '<' if chars.next_if(|&(_, c)| c == '=').is_some() => Node::Operator(Operator::Le),
+            '<' => Node::Operator(Operator::Lt),
+            '>' if chars.next_if(|&(_, c)| c == '=').is_some() => Node::Operator(Operator::Ge),
+            '>' => Node::Operator(Operator::Gt),
+            '=' if chars.next_if(|&(_, c)| c == '=').is_some() => Node::Operator(Operator::Eq),
+            '!' if chars.next_if(|&(_, c)| c == '=').is_some() => Node::Operator(Operator::Ne),
+            '!' => Node::Unary(UnaryOperator::Not),
+            '&' if chars.next_if(|&(_, c)| c == '&').is_some() => Node::Operator(Operator::And),
+            '|' if chars.next_if(|&(_, c)| c == '|').is_some() => Node::Operator(Operator::Or),
+            c if is_identifier_start(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| is_identifier_continue(c)) {
+                    end = i + c.len_utf8();
+                }
+                let name = &source[start..end];
+
+                // An identifier followed by parens is a call, anything else is a variable
+                if let Some(keyword) = keyword(name) {
+                    keyword
+                } else if source[end..].trim_start().starts_with('(') {
+                    match Function::from_name(name) {
+                        Some(function) => Node::Function(function),
+                        None => {
+                            return Err(ParseError {
+                                error: ExpressionConstructionError::UnknownFunction,
+                                span: start..end,
+                            })
+                        }
+                    }
+                } else {
+                    Node::Variable(name.to_string())
+                }
+            }
+            c if c.is_ascii_digit() || (c == '.' && chars.peek().is_some_and(|&(_, c)| c.is_ascii_digit())) => {
+                let end = number_end(source, start);
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                match source[start..end].parse() {
+                    Ok(value) => Node::Literal(value),
+                    Err(_) => {
+                        return Err(ParseError {
+                            error: ExpressionConstructionError::UnknownToken,
+                            span: start..end,
+                        })
+                    }
+                }
+            }
+            c => {
+                return Err(ParseError {
+                    error: ExpressionConstructionError::UnknownToken,
+                    span: start..start + c.len_utf8(),
+                })
+            }
+        };
+
+        let end = chars.peek().map_or(source.len(), |&(i, _)| i);
+        tokens.push((node, start..end));
+    }
+
+    Ok(tokens)
+}
+
+/// Finds the end of a number like `12`, `1.25`, `.5` or `2.5e-3` starting at `start`
+fn number_end(source: &str, start: usize) -> usize {
+    let bytes = source.as_bytes();
+    let digits = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+
+    let mut end = digits(start);
+    if end < bytes.len() && bytes[end] == b'.' {
+        end = digits(end + 1);
+    }
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exponent = end + 1;
+        if exponent < bytes.len() && (bytes[exponent] == b'+' || bytes[exponent] == b'-') {
+            exponent += 1;
+        }
+        if exponent < bytes.len() && bytes[exponent].is_ascii_digit() {
+            end = digits(exponent);
+        }
+    }
+    end
+}
+
+fn keyword(name: &str) -> Option<Node> {
+    match name {
+        "and" => Some(Node::Operator(Operator::And)),
+        "or" => Some(Node::Operator(Operator::Or)),
+        "not" => Some(Node::Unary(UnaryOperator::Not)),
+        _ => None,
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(source: &str) -> ParseError {
+        Expression::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn same_nodes_as_from_infix() {
+        let parsed = Expression::parse("a + (b - c) * d").unwrap();
+        let built = Expression::from_infix(&[
+            Node::Variable("a".into()),
+            Node::Operator(Operator::Add),
+            Node::LeftParens,
+            Node::Variable("b".into()),
+            Node::Operator(Operator::Sub),
+            Node::Variable("c".into()),
+            Node::RightParens,
+            Node::Operator(Operator::Mul),
+            Node::Variable("d".into()),
+        ])
+        .unwrap();
+
+        assert_eq!(parsed.nodes, built.nodes);
+        assert_eq!(parsed.variables(), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn repeated_variables() {
+        let parsed = Expression::parse("price_2 * price_2 + qty").unwrap();
+        assert_eq!(parsed.variables(), ["price_2", "qty"]);
+        assert_eq!(parsed.operands, [0, 0, 1]);
+    }
+
+    #[test]
+    fn literals() {
+        let parsed = Expression::parse("price * 1.25 + .5 - 2e3 / 1E-2").unwrap();
+        assert_eq!(parsed.variables(), ["price"]);
+
+        let literals = parsed
+            .nodes
+            .iter()
+            .filter_map(|n| match n {
+                Node::Literal(value) => Some(*value),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(literals, [1.25, 0.5, 2e3, 1e-2]);
+    }
+
+    #[test]
+    fn literal_followed_by_identifier() {
+        let err = parse_err("2e");
+        assert_eq!(err.error, ExpressionConstructionError::MissingOperator);
+        assert_eq!(err.span, 1..2);
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        let parsed = Expression::parse("a ^ b ^ c").unwrap();
+        let expected = Expression::parse("a ^ (b ^ c)").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+    }
+
+    #[test]
+    fn unary_operators() {
+        let parsed = Expression::parse("-a ^ 2").unwrap();
+        let expected = Expression::parse("-(a ^ 2)").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+
+        let parsed = Expression::parse("a * -b").unwrap();
+        let expected = Expression::parse("a * (-b)").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+
+        let parsed = Expression::parse("a - +b").unwrap();
+        let expected = Expression::parse("a - b").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+
+        let parsed = Expression::parse("2 ^ -a * b").unwrap();
+        let expected = Expression::parse("(2 ^ (-a)) * b").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+    }
+
+    #[test]
+    fn function_calls() {
+        let parsed = Expression::parse("max(a, 0) + clamp(sqrt(b) * 2, min(c, d), 10)").unwrap();
+        assert_eq!(parsed.variables(), ["a", "b", "c", "d"]);
+
+        let var = |name: &str| Node::Variable(name.into());
+        let expected = [
+            var("a"),
+            Node::Literal(0.0),
+            Node::Function(Function::Max),
+            var("b"),
+            Node::Function(Function::Sqrt),
+            Node::Literal(2.0),
+            Node::Operator(Operator::Mul),
+            var("c"),
+            var("d"),
+            Node::Function(Function::Min),
+            Node::Literal(10.0),
+            Node::Function(Function::Clamp),
+            Node::Operator(Operator::Add),
+        ];
+        assert_eq!(parsed.nodes, expected);
+    }
+
+    #[test]
+    fn function_names_are_not_reserved() {
+        let parsed = Expression::parse("max + min(max, 1)").unwrap();
+        assert_eq!(parsed.variables(), ["max"]);
+    }
+
+    #[test]
+    fn invalid_calls() {
+        let err = parse_err("a + maxx(a, b)");
+        assert_eq!(err.error, ExpressionConstructionError::UnknownFunction);
+        assert_eq!(err.span, 4..8);
+
+        let err = parse_err("1 + clamp(a, b)");
+        let expected = ExpressionConstructionError::InvalidArity {
+            function: Function::Clamp,
+            expected: 3,
+            found: 2,
+        };
+        assert_eq!(err.error, expected);
+        assert_eq!(err.span, 4..9);
+
+        let err = parse_err("abs()");
+        assert!(matches!(
+            err.error,
+            ExpressionConstructionError::InvalidArity { found: 0, .. }
+        ));
+
+        let err = parse_err("min(a, )");
+        assert_eq!(err.error, ExpressionConstructionError::MissingOperand);
+        assert_eq!(err.span, 7..8);
+
+        let err = parse_err("min(a - , b)");
+        assert_eq!(err.error, ExpressionConstructionError::DanglingOperator);
+        assert_eq!(err.span, 6..7);
+
+        let err = parse_err("(a, b)");
+        assert_eq!(err.error, ExpressionConstructionError::MisplacedSeparator);
+        assert_eq!(err.span, 2..3);
+
+        let err = parse_err("max(a, (b, c))");
+        assert_eq!(err.error, ExpressionConstructionError::MisplacedSeparator);
+        assert_eq!(err.span, 9..10);
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        let parsed = Expression::parse("a + 1 >= b * 2 and not c < d || e != f").unwrap();
+        let expected = Expression::parse("(((a + 1) >= (b * 2)) && !(c < d)) or (e != f)").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+
+        let parsed = Expression::parse("a<=b == c>d").unwrap();
+        let expected = Expression::parse("(a <= b) == (c > d)").unwrap();
+        assert_eq!(parsed.nodes, expected.nodes);
+    }
+
+    #[test]
+    fn if_function() {
+        let parsed = Expression::parse("if(a > b, a - b, 0)").unwrap();
+        assert_eq!(parsed.nodes.last(), Some(&Node::Function(Function::If)));
+        assert_eq!(parsed.variables(), ["a", "b"]);
+    }
+
+    #[test]
+    fn unknown_token() {
+        let err = parse_err("a + b % 2");
+        assert_eq!(err.error, ExpressionConstructionError::UnknownToken);
+        assert_eq!(err.span, 6..7);
+
+        let err = parse_err("a = b");
+        assert_eq!(err.error, ExpressionConstructionError::UnknownToken);
+        assert_eq!(err.span, 2..3);
+    }
+
+    #[test]
+    fn dangling_operator() {
+        let err = parse_err("a + b *");
+        assert_eq!(err.error, ExpressionConstructionError::DanglingOperator);
+        assert_eq!(err.span, 6..7);
+
+        let err = parse_err("(a -) + b");
+        assert_eq!(err.error, ExpressionConstructionError::DanglingOperator);
+        assert_eq!(err.span, 3..4);
+
+        let err = parse_err("a + -");
+        assert_eq!(err.error, ExpressionConstructionError::DanglingOperator);
+        assert_eq!(err.span, 4..5);
+    }
+
+    #[test]
+    fn missing_operand() {
+        let err = parse_err("a * / b");
+        assert_eq!(err.error, ExpressionConstructionError::MissingOperand);
+        assert_eq!(err.span, 4..5);
+
+        let err = parse_err("a + ()");
+        assert_eq!(err.error, ExpressionConstructionError::MissingOperand);
+        assert_eq!(err.span, 5..6);
+
+        let err = parse_err("a + (");
+        assert_eq!(err.error, ExpressionConstructionError::MissingOperand);
+        assert_eq!(err.span, 5..5);
+    }
+
+    #[test]
+    fn missing_operator() {
+        let err = parse_err("price tax");
+        assert_eq!(err.error, ExpressionConstructionError::MissingOperator);
+        assert_eq!(err.span, 6..9);
+    }
+
+    #[test]
+    fn unbalanced_parens() {
+        let err = parse_err("(a + b");
+        assert_eq!(err.error, ExpressionConstructionError::UbalancedParens);
+        assert_eq!(err.span, 0..1);
+
+        let err = parse_err("a + b)");
+        assert_eq!(err.error, ExpressionConstructionError::UbalancedParens);
+        assert_eq!(err.span, 5..6);
+    }
+
+    #[test]
+    fn invalid_window_size() {
+        let err = parse_err("lag(x, 1.5)");
+        assert_eq!(err.error, ExpressionConstructionError::InvalidWindowSize(0));
+        assert_eq!(err.span, 7..10);
+
+        let err = parse_err("rolling_sum(x, a + 1) * 2");
+        assert_eq!(err.error, ExpressionConstructionError::InvalidWindowSize(1));
+        assert_eq!(err.span, 17..18);
+    }
+
+    #[test]
+    fn empty() {
+        let err = parse_err("   ");
+        assert_eq!(err.error, ExpressionConstructionError::EmptyExpression);
+        assert_eq!(err.span, 0..3);
+    }
+}
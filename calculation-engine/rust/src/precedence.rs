@@ -1,6 +1,13 @@
 use std::sync::LazyLock;
 
-use crate::Node;
+use crate::{Node, Operator, UnaryOperator};
+
+/// Binds tighter than `*` and `/`, but `-a ^ b` is still `-(a ^ b)`
+/// since `^` is right associative with the same precedence
+pub(crate) const SIGN_PRECEDENCE: i32 = 8;
+
+/// Binds looser than comparisons, `not a > b` is `not (a > b)`
+const NOT_PRECEDENCE: i32 = 3;
 
 struct Precedence {
     min: i32,
@@ -8,9 +15,23 @@ struct Precedence {
 }
 static PRECEDENCE: LazyLock<Precedence> = LazyLock::new(|| {
     #[derive(Clone)]
-    struct Op(char, i32);
+    struct Op(Operator, i32);
 
-    let ops = [Op('+', 1), Op('-', 1), Op('*', 2), Op('/', 2), Op('^', 3)];
+    let ops = [
+        Op(Operator::Or, 1),
+        Op(Operator::And, 2),
+        Op(Operator::Eq, 4),
+        Op(Operator::Ne, 4),
+        Op(Operator::Lt, 5),
+        Op(Operator::Le, 5),
+        Op(Operator::Gt, 5),
+        Op(Operator::Ge, 5),
+        Op(Operator::Add, 6),
+        Op(Operator::Sub, 6),
+        Op(Operator::Mul, 7),
+        Op(Operator::Div, 7),
+        Op(Operator::Pow, 8),
+    ];
     let min = ops.iter().map(|v| v.0 as i32).min().unwrap();
     let max = ops.iter().map(|v| v.0 as i32).max().unwrap();
 
@@ -37,9 +58,15 @@ static PRECEDENCE: LazyLock<Precedence> = LazyLock::new(|| {
     }
 });
 
-pub(crate) fn precedence(n: Node) -> i32 {
+pub(crate) fn precedence(n: &Node) -> i32 {
     match n {
-        Node::Operator(value) => PRECEDENCE.values[((value as i32) - PRECEDENCE.min) as usize],
+        Node::Operator(value) => PRECEDENCE.values[((*value as i32) - PRECEDENCE.min) as usize],
+        Node::Unary(UnaryOperator::Not) => NOT_PRECEDENCE,
+        Node::Unary(_) => SIGN_PRECEDENCE,
         _ => -1i32,
     }
 }
+
+pub(crate) fn is_right_associative(n: &Node) -> bool {
+    matches!(n, Node::Operator(Operator::Pow))
+}
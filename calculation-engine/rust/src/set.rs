@@ -0,0 +1,56 @@
+use crate::engine::element::Element;
+use crate::engine::vectorized::BatchEngine;
+use crate::{Expression, ExpressionConstructionError};
+
+/// Expressions that are evaluated together over the same input columns, see `BatchEngine`.
+#[derive(Clone, Debug)]
+pub struct ExpressionSet {
+    expressions: Vec<Expression>,
+    /// Distinct variable names of all expressions, in order of first appearance.
+    /// Each input column belongs to the variable at the same index.
+    variables: Vec<String>,
+}
+
+impl ExpressionSet {
+    pub fn new(expressions: impl IntoIterator<Item = Expression>) -> Self {
+        let mut expressions = expressions.into_iter().collect::<Vec<_>>();
+
+        let mut variables: Vec<String> = Vec::new();
+        for variable in expressions.iter().flat_map(|expression| &expression.variables) {
+            if !variables.contains(variable) {
+                variables.push(variable.clone());
+            }
+        }
+
+        // Every expression reads its operands from the input columns of the whole set
+        for expression in &mut expressions {
+            expression.operands = Expression::operands(&expression.nodes, &mut variables);
+            expression.variables = variables.clone();
+        }
+
+        Self { expressions, variables }
+    }
+
+    /// The expressions in the order they were added, each of them takes the input columns of the whole set
+    pub fn expressions(&self) -> &[Expression] {
+        &self.expressions
+    }
+
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    pub fn to_vectorized_engine(self) -> BatchEngine {
+        BatchEngine::new(self)
+    }
+
+    /// Same as `Expression::to_typed_vectorized_engine`, for every expression of the set
+    pub fn to_typed_vectorized_engine<T: Element, const N: usize>(
+        self,
+    ) -> Result<BatchEngine<T, N>, ExpressionConstructionError> {
+        for expression in &self.expressions {
+            expression.check_type::<T>()?;
+        }
+        Ok(BatchEngine::new(self))
+    }
+}